@@ -1,11 +1,13 @@
 local _M = {}
 
-function _M.run()
+function _M.run(request)
     local headers = { ["Content-type"] = "text/html", ["Custom"] = "test lua" }
 
     local function hello_text()
         coroutine.yield("<html><body>")
         coroutine.yield("<p>Hello Wsapi!</p>")
+        coroutine.yield("<p>route uri: " .. request.uri .. "</p>")
+        coroutine.yield("<p>" .. (request.header("User-Agent")[1] or "") .. "</p>")
         coroutine.yield("</body></html>")
     end
 
@@ -1,3 +1,4 @@
+use crate::script::engine_pool::BusyGuard;
 use async_std::task;
 use futures::channel::mpsc;
 use std;
@@ -42,17 +43,54 @@ pub struct ScriptEvent {
     pub(crate) sender: Sender<ScriptResultEvent>,
     pub(crate) location: String,
     pub(crate) request: RequestData,
+    pub(crate) deadline: Option<std::time::Instant>,
+    pub(crate) guard: Option<BusyGuard>,
 }
 
+/// A response is sent as one event, or streamed as several ones: the first event carries the
+/// status and headers, the following ones only body chunks.
 pub struct ScriptResultEvent {
     pub(crate) result: StrErrResult<ResponseData>,
+    /// Whether the body follows in chunks after the head.
+    pub(crate) streaming: bool,
 }
 
 pub struct RequestData {
     pub(crate) headers: http::HeaderMap,
     pub(crate) body: bytes::Bytes,
+    pub(crate) method: String,
     pub(crate) uri: String,
+    pub(crate) path: String,
     pub(crate) query: String,
+    pub(crate) params: HashMap<String, String>,
+    pub(crate) remote_addr: Option<String>,
+    pub(crate) version: String,
+}
+
+impl RequestData {
+    /// Decoded query pairs grouped by name in order of appearance, a repeated name keeps all
+    /// of its values.
+    pub(crate) fn search_params(&self) -> Vec<(String, Vec<String>)> {
+        let mut params: Vec<(String, Vec<String>)> = vec![];
+        for (k, v) in url::form_urlencoded::parse(self.query.as_bytes()) {
+            match params.iter_mut().find(|(name, _)| name.as_str() == k) {
+                Some((_, values)) => values.push(v.into_owned()),
+                None => params.push((k.into_owned(), vec![v.into_owned()])),
+            }
+        }
+        params
+    }
+
+    pub(crate) fn cookies(&self) -> Vec<(String, String)> {
+        self.headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| cookie::Cookie::parse(pair.trim()).ok())
+            .map(|c| (c.name().to_string(), c.value().to_string()))
+            .collect()
+    }
 }
 
 pub struct ResponseData {
@@ -60,3 +98,122 @@ pub struct ResponseData {
     pub(crate) body: bytes::Bytes,
     pub(crate) status: u16,
 }
+
+impl ResponseData {
+    pub(crate) fn error(status: u16, message: String) -> Self {
+        ResponseData {
+            headers: HashMap::new(),
+            body: bytes::Bytes::from(message),
+            status,
+        }
+    }
+}
+
+/// Sends the response of a script event back to the server, at once or streamed.
+pub(crate) struct ResponseSender {
+    sender: Sender<ScriptResultEvent>,
+    head_sent: bool,
+}
+
+impl ResponseSender {
+    pub(crate) fn new(sender: Sender<ScriptResultEvent>) -> Self {
+        ResponseSender {
+            sender,
+            head_sent: false,
+        }
+    }
+
+    fn send(&mut self, result: StrErrResult<ResponseData>) -> StrErrResult<()> {
+        self.sender
+            .unbounded_send(ScriptResultEvent {
+                result,
+                streaming: self.head_sent,
+            })
+            .map_err(|e| format!("Response is closed: {}", e))
+    }
+
+    /// Starts a streamed response with the status and headers of `head`.
+    pub(crate) fn send_head(&mut self, head: ResponseData) -> StrErrResult<()> {
+        self.head_sent = true;
+        self.send(Ok(head))
+    }
+
+    pub(crate) fn send_chunk(&mut self, chunk: bytes::Bytes) -> StrErrResult<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        self.send(Ok(ResponseData {
+            headers: HashMap::new(),
+            body: chunk,
+            status: 200,
+        }))
+    }
+
+    /// Sends the whole response, or ends a streamed one (aborted if `result` is an error).
+    pub(crate) fn finish(mut self, result: StrErrResult<ResponseData>) {
+        let sent = match result {
+            Ok(_) if self.head_sent => Ok(()),
+            result => self.send(result),
+        };
+        if let Err(e) = sent {
+            log::debug!("Cannot send script result: {}", e);
+        }
+        self.sender.close_channel();
+    }
+
+    /// Responds with an error, the body is aborted if the response is already streaming.
+    pub(crate) fn fail(self, data: ResponseData) {
+        if self.head_sent {
+            let message = String::from_utf8_lossy(&data.body).to_string();
+            self.finish(Err(message))
+        } else {
+            self.finish(Ok(data))
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn request(query: &str, cookie: &str) -> RequestData {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::COOKIE, cookie.parse().unwrap());
+        RequestData {
+            headers,
+            body: bytes::Bytes::new(),
+            method: "GET".to_string(),
+            uri: format!("/test?{}", query),
+            path: "/test".to_string(),
+            query: query.to_string(),
+            params: HashMap::new(),
+            remote_addr: None,
+            version: "1.1".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_search_params() {
+        let req = request("a=1&b=x+y&a=2&c=%E4%BD%A0", "");
+        assert_eq!(
+            req.search_params(),
+            vec![
+                ("a".to_string(), vec!["1".to_string(), "2".to_string()]),
+                ("b".to_string(), vec!["x y".to_string()]),
+                ("c".to_string(), vec!["你".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cookies() {
+        let req = request("", "session=abc; theme=dark;broken");
+        assert_eq!(
+            req.cookies(),
+            vec![
+                ("session".to_string(), "abc".to_string()),
+                ("theme".to_string(), "dark".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use async_compression::futures::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder};
+use async_compression::Level;
+use futures::future::{BoxFuture, TryFutureExt};
+use futures::io::BufReader;
+use http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY,
+};
+use http::{Method, StatusCode};
+use http_service::{Body, HttpService, Request, Response};
+
+/// Quality of brotli for responses compressed on the fly, the default one is too slow for it.
+const BROTLI_QUALITY: u32 = 4;
+
+static COMPRESSIBLE_TYPES: &[&str] = &[
+    "application/javascript",
+    "application/json",
+    "application/wasm",
+    "application/x-javascript",
+    "application/xml",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+/// Encodings of responses compressed on the fly, by order of preference.
+pub(crate) static SUPPORTED: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+impl Encoding {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the encoding of `supported` with the highest quality in an `Accept-Encoding` header,
+/// the first one wins a tie.
+pub(crate) fn negotiate(accept: &str, supported: &[Encoding]) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut qualities = vec![];
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let quality = parts
+            .map(|p| p.trim())
+            .filter(|p| p.starts_with("q=") || p.starts_with("Q="))
+            .filter_map(|p| p[2..].parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(quality);
+        } else if !name.is_empty() {
+            qualities.push((name, quality));
+        }
+    }
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in supported {
+        let quality = qualities
+            .iter()
+            .find(|(name, _)| {
+                name == encoding.name() || (encoding == Encoding::Gzip && name == "x-gzip")
+            })
+            .map(|(_, quality)| *quality)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.map_or(true, |(_, q)| quality > q) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether a response of the content type gains from compression, media types are usually
+/// compressed already and event streams must not be buffered.
+fn compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    if mime == "text/event-stream" {
+        return false;
+    }
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.contains(&mime.as_str())
+}
+
+/// Appends `name` to the `Vary` header unless one of its values has it already.
+pub(crate) fn append_vary(headers: &mut HeaderMap, name: &'static str) {
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(name));
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static(name));
+    }
+}
+
+/// Adds `Accept-Encoding` to the `Vary` header, caches must not mix encoded responses.
+pub(crate) fn vary_encoding(headers: &mut HeaderMap) {
+    append_vary(headers, "accept-encoding");
+}
+
+fn encode(body: Body, encoding: Encoding) -> Body {
+    let reader = BufReader::new(body);
+    match encoding {
+        Encoding::Brotli => Body::from_reader(BufReader::new(BrotliEncoder::with_quality(
+            reader,
+            Level::Precise(BROTLI_QUALITY),
+        ))),
+        Encoding::Gzip => Body::from_reader(BufReader::new(GzipEncoder::new(reader))),
+        Encoding::Deflate => Body::from_reader(BufReader::new(ZlibEncoder::new(reader))),
+    }
+}
+
+/// Compresses the body of a response of at least `min_size` bytes with `encoding`. A response
+/// without `Content-Length` is streamed as is, the encoder would hold its chunks back.
+fn compress(resp: Response, encoding: Option<Encoding>, min_size: usize) -> Response {
+    let headers = resp.headers();
+    let status = resp.status();
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !compressible(content_type)
+        || headers.contains_key(CONTENT_ENCODING)
+        || headers.contains_key(CONTENT_RANGE)
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return resp;
+    }
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+
+    let (mut parts, body) = resp.into_parts();
+    vary_encoding(&mut parts.headers);
+    let encoding = match (encoding, length) {
+        (Some(encoding), Some(length)) if length >= min_size => encoding,
+        _ => return Response::from_parts(parts, body),
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove(ACCEPT_RANGES);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    // the encoded body is not byte for byte the one of a strong etag
+    let weak_etag = parts
+        .headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.starts_with("W/"))
+        .and_then(|v| HeaderValue::from_str(&format!("W/{}", v)).ok());
+    if let Some(etag) = weak_etag {
+        parts.headers.insert(ETAG, etag);
+    }
+    Response::from_parts(parts, encode(body, encoding))
+}
+
+/// Compresses the responses of the wrapped service, negotiated with `Accept-Encoding`.
+pub(crate) struct Compress<S> {
+    inner: S,
+    min_size: Option<usize>,
+}
+
+impl<S> Compress<S> {
+    /// Responses under `min_size` bytes are sent as is, `None` disables compression.
+    pub(crate) fn new(inner: S, min_size: Option<usize>) -> Self {
+        Compress { inner, min_size }
+    }
+}
+
+impl<S> HttpService for Compress<S>
+where
+    S: HttpService,
+    <S::ResponseFuture as futures::TryFuture>::Error: Send,
+{
+    type Connection = S::Connection;
+    type ConnectionFuture = S::ConnectionFuture;
+    type ResponseFuture =
+        BoxFuture<'static, Result<Response, <S::ResponseFuture as futures::TryFuture>::Error>>;
+
+    fn connect(&self) -> Self::ConnectionFuture {
+        self.inner.connect()
+    }
+
+    fn respond(&self, conn: &mut Self::Connection, req: Request) -> Self::ResponseFuture {
+        let min_size = self.min_size;
+        let encoding = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| req.method() != Method::HEAD)
+            .and_then(|v| negotiate(v, SUPPORTED));
+        let resp = self.inner.respond(conn, req).into_future();
+        Box::pin(async move {
+            let resp = resp.await?;
+            match min_size {
+                Some(min_size) => Ok(compress(resp, encoding, min_size)),
+                None => Ok(resp),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, Cursor};
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            negotiate("gzip, deflate, br", SUPPORTED),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(negotiate("gzip, deflate", SUPPORTED), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate("br;q=0.5, gzip;q=0.8", SUPPORTED),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate("deflate, br;q=0", SUPPORTED),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate("*", SUPPORTED), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=0, *;q=0.1", &[Encoding::Gzip]), None);
+        assert_eq!(negotiate("identity", SUPPORTED), None);
+        assert_eq!(negotiate("", SUPPORTED), None);
+    }
+
+    #[test]
+    fn test_compress() {
+        async_std::task::block_on(async {
+            let text = "laputa ".repeat(100);
+            let resp = http::Response::builder()
+                .header(CONTENT_TYPE, "text/plain")
+                .header(CONTENT_LENGTH, text.len())
+                .body(Body::from(text.clone()))
+                .unwrap();
+            let resp = compress(resp, Some(Encoding::Gzip), 64);
+            assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+            assert_eq!(resp.headers()[VARY], "accept-encoding");
+            let compressed = resp.into_body().into_vec().await.unwrap();
+            assert!(compressed.len() < text.len());
+            let mut decoder = async_compression::futures::bufread::GzipDecoder::new(
+                BufReader::new(Cursor::new(compressed)),
+            );
+            let mut decoded = String::new();
+            decoder.read_to_string(&mut decoded).await.unwrap();
+            assert_eq!(decoded, text);
+
+            // small, binary and streamed responses are sent as is
+            let resp = http::Response::builder()
+                .header(CONTENT_TYPE, "text/plain")
+                .header(CONTENT_LENGTH, 5)
+                .body(Body::from("small"))
+                .unwrap();
+            let resp = compress(resp, Some(Encoding::Gzip), 64);
+            assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(resp.into_body().into_vec().await.unwrap(), b"small");
+            let resp = http::Response::builder()
+                .header(CONTENT_TYPE, "image/png")
+                .header(CONTENT_LENGTH, text.len())
+                .body(Body::from(text.clone()))
+                .unwrap();
+            let resp = compress(resp, Some(Encoding::Gzip), 64);
+            assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+            let resp = http::Response::builder()
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from(text.clone()))
+                .unwrap();
+            let resp = compress(resp, Some(Encoding::Gzip), 64);
+            assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(resp.headers()[VARY], "accept-encoding");
+        });
+    }
+}
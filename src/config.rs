@@ -0,0 +1,467 @@
+use std::fmt::Formatter;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tide::http::Method;
+
+static DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+static UNIX_PREFIX: &str = "unix:";
+static TLS_PREFIX: &str = "tls:";
+const DEFAULT_SCRIPT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_EVENT_LOOP_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_HTTP_CLIENT_TIMEOUT_SECS: u64 = 30;
+pub(crate) const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+    /// HTTPS on a TCP address, with the certificates of `ServerConfig::tls`.
+    Tls(String),
+}
+
+impl FromStr for ListenAddr {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(UNIX_PREFIX) {
+            Ok(ListenAddr::Unix(PathBuf::from(&s[UNIX_PREFIX.len()..])))
+        } else if s.starts_with(TLS_PREFIX) {
+            Ok(ListenAddr::Tls(s[TLS_PREFIX.len()..].to_string()))
+        } else {
+            Ok(ListenAddr::Tcp(s.to_string()))
+        }
+    }
+}
+
+impl From<&str> for ListenAddr {
+    fn from(s: &str) -> Self {
+        s.parse().unwrap()
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr.to_string())
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            ListenAddr::Tls(addr) => write!(f, "{}{}", TLS_PREFIX, addr),
+        }
+    }
+}
+
+/// Address a listener is actually bound to, with the real port when port 0 was requested.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BoundAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Tls(SocketAddr),
+}
+
+impl BoundAddr {
+    /// The socket address of a TCP listener, with or without TLS.
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            BoundAddr::Tcp(addr) | BoundAddr::Tls(addr) => Some(*addr),
+            BoundAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for BoundAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoundAddr::Tcp(addr) => write!(f, "http://{}", addr),
+            BoundAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            BoundAddr::Tls(addr) => write!(f, "https://{}", addr),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dispatch {
+    RoundRobin,
+    LeastBusy,
+}
+
+impl FromStr for Dispatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Dispatch::RoundRobin),
+            "least_busy" => Ok(Dispatch::LeastBusy),
+            other => Err(format!("Unknown dispatch strategy: {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    pub(crate) workers: usize,
+    pub(crate) dispatch: Dispatch,
+    pub(crate) memory_limit: Option<usize>,
+    pub(crate) event_loop_timeout: Duration,
+}
+
+impl EngineConfig {
+    pub fn new() -> Self {
+        EngineConfig {
+            workers: 1,
+            dispatch: Dispatch::RoundRobin,
+            memory_limit: None,
+            event_loop_timeout: Duration::from_secs(DEFAULT_EVENT_LOOP_TIMEOUT_SECS),
+        }
+    }
+
+    /// Number of engine threads, each one has its own script vm.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    pub fn dispatch(mut self, dispatch: Dispatch) -> Self {
+        self.dispatch = dispatch;
+        self
+    }
+
+    /// Max heap size in bytes of each script vm. A request exceeding it fails with 500 and the
+    /// vm is replaced by a new one.
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Max time pending promises and timers of a javascript handler may keep the request open,
+    /// bounded by the script timeout of the route as well.
+    pub fn event_loop_timeout(mut self, timeout: Duration) -> Self {
+        self.event_loop_timeout = timeout;
+        self
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig::new()
+    }
+}
+
+/// HTTP methods handled by a route.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Methods {
+    Any,
+    Set(Vec<Method>),
+}
+
+impl Methods {
+    pub fn any() -> Self {
+        Methods::Any
+    }
+
+    pub fn set(methods: impl IntoIterator<Item = Method>) -> Self {
+        Methods::Set(methods.into_iter().collect())
+    }
+}
+
+impl From<Method> for Methods {
+    fn from(method: Method) -> Self {
+        Methods::Set(vec![method])
+    }
+}
+
+impl From<Vec<Method>> for Methods {
+    fn from(methods: Vec<Method>) -> Self {
+        Methods::Set(methods)
+    }
+}
+
+impl From<&[Method]> for Methods {
+    fn from(methods: &[Method]) -> Self {
+        Methods::Set(methods.to_vec())
+    }
+}
+
+impl std::fmt::Display for Methods {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Methods::Any => write!(f, "ANY"),
+            Methods::Set(methods) => {
+                let names = methods.iter().map(|m| m.as_str()).collect::<Vec<&str>>();
+                write!(f, "{}", names.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RouteOptions {
+    pub(crate) dedicated: bool,
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl RouteOptions {
+    pub fn new() -> Self {
+        RouteOptions::default()
+    }
+
+    /// Runs the route on its own engine workers instead of the ones shared by all routes,
+    /// so it does not share script state or wait behind other routes.
+    pub fn dedicated(mut self, dedicated: bool) -> Self {
+        self.dedicated = dedicated;
+        self
+    }
+
+    /// Overrides `ServerConfig::script_timeout` for the route.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// Outbound HTTP requests made by scripts, e.g. `fetch` in javascript.
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    pub(crate) allowed_hosts: Vec<String>,
+    pub(crate) timeout: Duration,
+}
+
+impl HttpClientConfig {
+    pub fn new() -> Self {
+        HttpClientConfig {
+            allowed_hosts: vec![],
+            timeout: Duration::from_secs(DEFAULT_HTTP_CLIENT_TIMEOUT_SECS),
+        }
+    }
+
+    /// Allows requests to a host: a host name (`api.example.com`), a host with port
+    /// (`localhost:8080`), a wildcard subdomain (`*.example.com`) or `*` for any host.
+    /// Requests to hosts not allowed fail, no host is allowed by default.
+    pub fn allow_host(mut self, host: impl Into<String>) -> Self {
+        self.allowed_hosts.push(host.into());
+        self
+    }
+
+    /// Max time of a request, including reading the response body.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig::new()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CertFiles {
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf,
+}
+
+/// Certificates of the TLS listeners, each one a PEM file of the certificate chain and a PEM
+/// file of its private key (PKCS#8 or RSA).
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub(crate) certs: Vec<CertFiles>,
+    pub(crate) watch: bool,
+}
+
+impl TlsConfig {
+    /// The default certificate, sent to clients without SNI or asking for a name no other
+    /// certificate is valid for.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            certs: vec![CertFiles {
+                cert: cert.into(),
+                key: key.into(),
+            }],
+            watch: true,
+        }
+    }
+
+    /// Adds a certificate sent to the clients asking by SNI for a name it is valid for.
+    pub fn sni_cert(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.certs.push(CertFiles {
+            cert: cert.into(),
+            key: key.into(),
+        });
+        self
+    }
+
+    /// Reloads the certificates when their files change on disk, enabled by default. New
+    /// connections use the new certificates, invalid ones are ignored.
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+}
+
+/// Proxies trusted to tell the client address with the `Forwarded`, `X-Forwarded-For` and
+/// `X-Real-IP` headers, the headers of other peers are ignored.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies {
+    pub(crate) ips: Vec<IpAddr>,
+    pub(crate) unix: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub(crate) listeners: Vec<ListenAddr>,
+    pub(crate) log_level: Option<String>,
+    pub(crate) js_engine: EngineConfig,
+    pub(crate) lua_engine: EngineConfig,
+    pub(crate) script_timeout: Option<Duration>,
+    pub(crate) http_client: HttpClientConfig,
+    pub(crate) compression: Option<usize>,
+    pub(crate) tls: Option<TlsConfig>,
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) handle_signals: bool,
+    pub(crate) trusted_proxies: TrustedProxies,
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        ServerConfig {
+            listeners: vec![],
+            log_level: None,
+            js_engine: EngineConfig::new(),
+            lua_engine: EngineConfig::new(),
+            script_timeout: Some(Duration::from_secs(DEFAULT_SCRIPT_TIMEOUT_SECS)),
+            http_client: HttpClientConfig::new(),
+            compression: None,
+            tls: None,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            handle_signals: true,
+            trusted_proxies: TrustedProxies::default(),
+        }
+    }
+
+    pub fn listen(mut self, addr: impl Into<ListenAddr>) -> Self {
+        self.listeners.push(addr.into());
+        self
+    }
+
+    pub fn listen_unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.listeners.push(ListenAddr::Unix(path.into()));
+        self
+    }
+
+    /// Listens for HTTPS, the certificates are set with `tls`.
+    pub fn listen_tls(mut self, addr: impl Into<String>) -> Self {
+        self.listeners.push(ListenAddr::Tls(addr.into()));
+        self
+    }
+
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    pub fn log_level(mut self, level: impl Into<String>) -> Self {
+        self.log_level = Some(level.into());
+        self
+    }
+
+    pub fn js_engine(mut self, config: EngineConfig) -> Self {
+        self.js_engine = config;
+        self
+    }
+
+    pub fn lua_engine(mut self, config: EngineConfig) -> Self {
+        self.lua_engine = config;
+        self
+    }
+
+    /// Max time a script handler may run before it is terminated and answered with 504,
+    /// `None` lets scripts run without limit.
+    pub fn script_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.script_timeout = timeout;
+        self
+    }
+
+    pub fn http_client(mut self, config: HttpClientConfig) -> Self {
+        self.http_client = config;
+        self
+    }
+
+    /// Compresses text responses of at least `min_size` bytes with gzip, deflate or brotli, as
+    /// accepted by the client. `None` disables compression, which is the default.
+    pub fn compression(mut self, min_size: Option<usize>) -> Self {
+        self.compression = min_size;
+        self
+    }
+
+    /// Max time to wait on shutdown for the requests in flight and the script engines, the
+    /// server stops anyway once it is over.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Shuts the server down gracefully on SIGINT or SIGTERM, enabled by default.
+    pub fn handle_signals(mut self, enabled: bool) -> Self {
+        self.handle_signals = enabled;
+        self
+    }
+
+    /// Trusts the client address told by the proxy at `ip`, none is trusted by default.
+    pub fn trusted_proxy(mut self, ip: IpAddr) -> Self {
+        self.trusted_proxies.ips.push(ip);
+        self
+    }
+
+    /// Trusts the client address told by the proxies connected to the Unix sockets.
+    pub fn trust_unix_proxy(mut self, trust: bool) -> Self {
+        self.trusted_proxies.unix = trust;
+        self
+    }
+
+    pub(crate) fn listen_addrs(&self) -> Vec<ListenAddr> {
+        if self.listeners.is_empty() {
+            vec![ListenAddr::Tcp(DEFAULT_LISTEN.to_string())]
+        } else {
+            self.listeners.clone()
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig::new()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addr_parse() {
+        assert_eq!(
+            ListenAddr::from("0.0.0.0:80"),
+            ListenAddr::Tcp("0.0.0.0:80".to_string())
+        );
+        assert_eq!(
+            ListenAddr::from("unix:/tmp/laputa.sock"),
+            ListenAddr::Unix(PathBuf::from("/tmp/laputa.sock"))
+        );
+        assert_eq!(
+            ListenAddr::from("tls:0.0.0.0:443"),
+            ListenAddr::Tls("0.0.0.0:443".to_string())
+        );
+        assert_eq!(
+            ServerConfig::new().listen_addrs(),
+            vec![ListenAddr::Tcp(DEFAULT_LISTEN.to_string())]
+        );
+    }
+}
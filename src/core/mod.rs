@@ -1,2 +1,3 @@
-mod net;
-
+pub(crate) mod net;
+pub(crate) mod peer;
+pub(crate) mod tls;
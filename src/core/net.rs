@@ -0,0 +1,263 @@
+use crate::config::{BoundAddr, ListenAddr};
+use async_std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_tls::server::TlsStream;
+use async_tls::TlsAcceptor;
+use futures::future::FutureObj;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::BoxStream;
+use futures::task::{Context, Poll, Spawn, SpawnError};
+use futures::StreamExt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+const MAX_PENDING_HANDSHAKES: usize = 128;
+
+#[derive(Copy, Clone)]
+pub(crate) struct Spawner;
+
+impl Spawn for &Spawner {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        async_std::task::spawn(future);
+        Ok(())
+    }
+}
+
+/// An accepted connection, with the address of the peer for TCP ones.
+pub(crate) enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<TlsStream<TcpStream>>, SocketAddr),
+}
+
+impl Connection {
+    /// Address of the peer, `None` for a Unix socket.
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(_, peer) | Connection::Tls(_, peer) => Some(*peer),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(s, _) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Connection::Tls(s, _) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(s, _) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Connection::Tls(s, _) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s, _) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(s) => Pin::new(s).poll_flush(cx),
+            Connection::Tls(s, _) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s, _) => Pin::new(s).poll_close(cx),
+            #[cfg(unix)]
+            Connection::Unix(s) => Pin::new(s).poll_close(cx),
+            Connection::Tls(s, _) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, std::path::PathBuf),
+    Tls(TcpListener, TlsAcceptor),
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: &ListenAddr, tls: Option<&TlsAcceptor>) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr.as_str()).await?)),
+            ListenAddr::Tls(addr) => match tls {
+                Some(acceptor) => {
+                    let listener = TcpListener::bind(addr.as_str()).await?;
+                    Ok(Listener::Tls(listener, acceptor.clone()))
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "No TLS certificate is configured",
+                )),
+            },
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path).await?;
+                let listener = UnixListener::bind(path).await?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(path) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Unix socket {} is not supported on this platform",
+                    path.display()
+                ),
+            )),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<BoundAddr> {
+        match self {
+            Listener::Tcp(l) => Ok(BoundAddr::Tcp(l.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(BoundAddr::Unix(path.clone())),
+            Listener::Tls(l, _) => Ok(BoundAddr::Tls(l.local_addr()?)),
+        }
+    }
+
+    pub(crate) fn incoming(self) -> BoxStream<'static, io::Result<Connection>> {
+        let acceptor = match &self {
+            Listener::Tls(_, acceptor) => Some(acceptor.clone()),
+            _ => None,
+        };
+        let stream = futures::stream::unfold(self, |listener| async move {
+            let conn = match &listener {
+                Listener::Tcp(l) | Listener::Tls(l, _) => {
+                    l.accept().await.map(|(s, peer)| Connection::Tcp(s, peer))
+                }
+                #[cfg(unix)]
+                Listener::Unix(l, _) => l.accept().await.map(|(s, _)| Connection::Unix(s)),
+            };
+            Some((conn, listener))
+        });
+        // A failed accept (e.g. too many open files) must not stop the whole server.
+        let conns = stream.filter_map(|conn| async move {
+            match conn {
+                Ok(conn) => Some(conn),
+                Err(e) => {
+                    log::error!("Error in accepting connection: {}", e);
+                    None
+                }
+            }
+        });
+        match acceptor {
+            None => conns.map(Ok).boxed(),
+            // handshakes run concurrently, a slow client does not hold the following ones
+            Some(acceptor) => conns
+                .map(move |conn| tls_accept(acceptor.clone(), conn))
+                .buffer_unordered(MAX_PENDING_HANDSHAKES)
+                .filter_map(|conn| async move {
+                    match conn {
+                        Ok(conn) => Some(Ok(conn)),
+                        Err(e) => {
+                            log::debug!("Error in TLS handshake: {}", e);
+                            None
+                        }
+                    }
+                })
+                .boxed(),
+        }
+    }
+}
+
+async fn tls_accept(acceptor: TlsAcceptor, conn: Connection) -> io::Result<Connection> {
+    let (stream, peer) = match conn {
+        Connection::Tcp(stream, peer) => (stream, peer),
+        conn => return Ok(conn),
+    };
+    let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT_SECS);
+    match async_std::future::timeout(timeout, acceptor.accept(stream)).await {
+        Ok(stream) => Ok(Connection::Tls(Box::new(stream?), peer)),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "TLS handshake timed out",
+        )),
+    }
+}
+
+/// Removes the socket file left by a server which is gone, a socket still accepting
+/// connections belongs to a running server and is not taken over.
+#[cfg(unix)]
+async fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => match UnixStream::connect(path).await {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Unix socket {} is in use by another server", path.display()),
+            )),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)
+            }
+            Err(e) => Err(e),
+        },
+        _ => Ok(()),
+    }
+}
+
+pub(crate) async fn bind_all(
+    addrs: &[ListenAddr],
+    tls: Option<TlsAcceptor>,
+) -> io::Result<(Vec<BoundAddr>, BoxStream<'static, io::Result<Connection>>)> {
+    let mut bound = Vec::with_capacity(addrs.len());
+    let mut streams = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let listener = Listener::bind(addr, tls.as_ref())
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("Cannot listen on {}: {}", addr, e)))?;
+        let local = listener.local_addr()?;
+        log::info!("Server is listening on: {}", local);
+        bound.push(local);
+        streams.push(listener.incoming());
+    }
+    Ok((bound, futures::stream::select_all(streams).boxed()))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_in_use() {
+        let path = std::env::temp_dir().join(format!("laputa-net-{}.sock", std::process::id()));
+        let addr = ListenAddr::Unix(path.clone());
+        async_std::task::block_on(async {
+            let listener = Listener::bind(&addr, None).await.unwrap();
+            // a running server keeps its socket
+            let e = Listener::bind(&addr, None).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+            // the socket file of a server which is gone is replaced
+            drop(listener);
+            assert!(path.exists());
+            Listener::bind(&addr, None).await.unwrap();
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+}
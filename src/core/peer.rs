@@ -0,0 +1,205 @@
+use crate::config::TrustedProxies;
+use http::HeaderMap;
+use http_service::{HttpService, Request};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Address of the client of a request, in the extensions of the request.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientAddr(pub(crate) Option<String>);
+
+/// Address of the client of a request served by `PeerService`.
+pub(crate) fn request_client_addr<State>(req: &tide::Request<State>) -> Option<String> {
+    req.local::<ClientAddr>().and_then(|addr| addr.0.clone())
+}
+
+/// Address of a hop: a bare IP, an IP with a port or a bracketed IPv6 address. `unknown`,
+/// obfuscated identifiers and other tokens are `None`.
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    let addr = addr.trim().trim_matches('"');
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            addr.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .ok()
+        })
+}
+
+/// Values of the `for` parameters of `Forwarded` headers, by order of the hops.
+fn forwarded_for(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element
+                .split(';')
+                .map(|pair| pair.trim())
+                .find(|pair| pair.len() > 4 && pair[..4].eq_ignore_ascii_case("for="))
+                .map(|pair| &pair[4..])
+        })
+        .collect()
+}
+
+/// Client address told by the proxy headers (`Forwarded`, `X-Forwarded-For` or `X-Real-IP`).
+/// Each proxy appends the address of its peer, so the last hop which is not a trusted proxy
+/// is the client, the ones before may be forged. Hops which are not IPs are skipped.
+fn forwarded_addr(headers: &HeaderMap, proxies: &TrustedProxies) -> Option<IpAddr> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<&str>>()
+            .join(",")
+    };
+    let forwarded = values("forwarded");
+    let mut hops: Vec<IpAddr> = forwarded_for(&forwarded)
+        .into_iter()
+        .filter_map(parse_ip)
+        .collect();
+    if hops.is_empty() {
+        hops = values("x-forwarded-for")
+            .split(',')
+            .filter_map(parse_ip)
+            .collect();
+    }
+    if hops.is_empty() {
+        hops = values("x-real-ip")
+            .split(',')
+            .filter_map(parse_ip)
+            .take(1)
+            .collect();
+    }
+    hops.iter()
+        .rev()
+        .find(|ip| !proxies.ips.contains(ip))
+        .or_else(|| hops.first())
+        .cloned()
+}
+
+/// Address of the client connected from `peer` (`None` for a Unix socket), the proxy headers
+/// are only read if the peer is a trusted proxy.
+pub(crate) fn client_addr(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    proxies: &TrustedProxies,
+) -> Option<String> {
+    let peer_ip = peer.map(|peer| peer.ip());
+    let trusted = match peer_ip {
+        Some(ip) => proxies.ips.contains(&ip),
+        None => proxies.unix,
+    };
+    let forwarded = if trusted {
+        forwarded_addr(headers, proxies)
+    } else {
+        None
+    };
+    forwarded.or(peer_ip).map(|ip| ip.to_string())
+}
+
+/// Serves the requests of one connection, with the client address in their extensions.
+pub(crate) struct PeerService<S> {
+    inner: Arc<S>,
+    peer: Option<SocketAddr>,
+    proxies: Arc<TrustedProxies>,
+}
+
+impl<S> PeerService<S> {
+    pub(crate) fn new(
+        inner: Arc<S>,
+        peer: Option<SocketAddr>,
+        proxies: Arc<TrustedProxies>,
+    ) -> Self {
+        PeerService {
+            inner,
+            peer,
+            proxies,
+        }
+    }
+}
+
+impl<S: HttpService> HttpService for PeerService<S> {
+    type Connection = S::Connection;
+    type ConnectionFuture = S::ConnectionFuture;
+    type ResponseFuture = S::ResponseFuture;
+
+    fn connect(&self) -> Self::ConnectionFuture {
+        self.inner.connect()
+    }
+
+    fn respond(&self, conn: &mut Self::Connection, mut req: Request) -> Self::ResponseFuture {
+        let addr = client_addr(self.peer, req.headers(), &self.proxies);
+        req.extensions_mut().insert(ClientAddr(addr));
+        self.inner.respond(conn, req)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_addr() {
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        let untrusted = TrustedProxies::default();
+        // the headers of a client connected directly are ignored
+        assert_eq!(
+            client_addr(Some(peer), &headers, &untrusted),
+            Some("10.0.0.1".to_string())
+        );
+        assert_eq!(client_addr(None, &headers, &untrusted), None);
+
+        let proxies = TrustedProxies {
+            ips: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            unix: true,
+        };
+        assert_eq!(
+            client_addr(Some(peer), &HeaderMap::new(), &proxies),
+            Some("10.0.0.1".to_string())
+        );
+        headers.insert("x-real-ip", "10.0.0.3".parse().unwrap());
+        assert_eq!(
+            client_addr(None, &headers, &proxies),
+            Some("1.2.3.4".to_string())
+        );
+        // a forged hop before the client is skipped, as well as the trusted proxies
+        headers.insert(
+            "x-forwarded-for",
+            "6.6.6.6, 1.2.3.4, 10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(
+            client_addr(Some(peer), &headers, &proxies),
+            Some("1.2.3.4".to_string())
+        );
+        headers.insert(
+            "forwarded",
+            "for=\"[2001:db8::17]:4711\";proto=http, for=\"10.0.0.2:8080\""
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            client_addr(Some(peer), &headers, &proxies),
+            Some("2001:db8::17".to_string())
+        );
+        // unknown and obfuscated hops are not addresses
+        headers.insert(
+            "forwarded",
+            "for=192.0.2.60:443, for=unknown, for=_hidden"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            client_addr(Some(peer), &headers, &proxies),
+            Some("192.0.2.60".to_string())
+        );
+        headers.insert("forwarded", "for=unknown".parse().unwrap());
+        assert_eq!(
+            client_addr(Some(peer), &headers, &proxies),
+            Some("1.2.3.4".to_string())
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use crate::config::{CertFiles, TlsConfig};
+use async_tls::TlsAcceptor;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+const WATCH_DELAY_MS: u64 = 500;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e)))
+}
+
+fn load_key(files: &CertFiles) -> io::Result<CertifiedKey> {
+    let pem = read_file(&files.cert)?;
+    let chain = pemfile::certs(&mut &pem[..])
+        .ok()
+        .filter(|chain| !chain.is_empty())
+        .ok_or_else(|| invalid_data(format!("No certificate in {}", files.cert.display())))?;
+
+    let pem = read_file(&files.key)?;
+    let key = pemfile::pkcs8_private_keys(&mut &pem[..])
+        .ok()
+        .and_then(|keys| keys.into_iter().next())
+        .or_else(|| {
+            pemfile::rsa_private_keys(&mut &pem[..])
+                .ok()
+                .and_then(|keys| keys.into_iter().next())
+        })
+        .ok_or_else(|| invalid_data(format!("No private key in {}", files.key.display())))?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| invalid_data(format!("Unsupported private key {}", files.key.display())))?;
+
+    let certified = CertifiedKey::new(chain, Arc::new(key));
+    certified.cross_check_end_entity_cert(None).map_err(|e| {
+        invalid_data(format!(
+            "Invalid certificate {}: {}",
+            files.cert.display(),
+            e
+        ))
+    })?;
+    Ok(certified)
+}
+
+/// Certificates of the TLS listeners. The first one is the default, the others are chosen by
+/// the server name the client asks for, if they are valid for it.
+struct CertResolver {
+    files: Vec<CertFiles>,
+    keys: RwLock<Vec<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn new(files: Vec<CertFiles>) -> io::Result<Self> {
+        let keys = files.iter().map(load_key).collect::<io::Result<_>>()?;
+        Ok(CertResolver {
+            files,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Loads the certificates again, the current ones are kept if one of them is invalid.
+    fn reload(&self) {
+        match self.files.iter().map(load_key).collect::<io::Result<_>>() {
+            Ok(keys) => {
+                *self.keys.write().unwrap() = keys;
+                log::info!("TLS certificates are reloaded");
+            }
+            Err(e) => log::error!("Cannot reload TLS certificates: {}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let keys = self.keys.read().unwrap();
+        if let Some(name) = client_hello.server_name() {
+            let matched = keys
+                .iter()
+                .skip(1)
+                .find(|key| key.cross_check_end_entity_cert(Some(name)).is_ok());
+            if let Some(key) = matched {
+                return Some(key.clone());
+            }
+        }
+        keys.first().cloned()
+    }
+}
+
+/// Watches the directories of the certificate files, tools like certbot replace the files
+/// instead of writing them. Stops with the server, when the resolver is dropped.
+fn watch(resolver: Weak<CertResolver>, files: &[CertFiles]) -> io::Result<()> {
+    let paths: HashSet<PathBuf> = files
+        .iter()
+        .flat_map(|f| vec![f.cert.clone(), f.key.clone()])
+        .map(|p| p.canonicalize().unwrap_or(p))
+        .collect();
+    let dirs: HashSet<PathBuf> = paths
+        .iter()
+        .filter_map(|p| p.parent().map(Path::to_path_buf))
+        .collect();
+    let to_io = |e: notify::Error| io::Error::new(io::ErrorKind::Other, e.to_string());
+
+    let (tx, events) = channel();
+    let mut watcher = notify::watcher(tx, Duration::from_millis(WATCH_DELAY_MS)).map_err(to_io)?;
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(to_io)?;
+    }
+    std::thread::Builder::new()
+        .name("tls-watcher".to_string())
+        .spawn(move || {
+            let _watcher = watcher;
+            for event in events {
+                let changed = match event {
+                    DebouncedEvent::Write(p)
+                    | DebouncedEvent::Create(p)
+                    | DebouncedEvent::Chmod(p)
+                    | DebouncedEvent::Remove(p) => paths.contains(&p),
+                    DebouncedEvent::Rename(from, to) => {
+                        paths.contains(&from) || paths.contains(&to)
+                    }
+                    DebouncedEvent::Rescan => true,
+                    _ => false,
+                };
+                if !changed {
+                    continue;
+                }
+                match resolver.upgrade() {
+                    Some(resolver) => resolver.reload(),
+                    None => break,
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Acceptor of the TLS connections, loads the certificates and watches them if configured.
+pub(crate) fn acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let resolver = Arc::new(CertResolver::new(config.certs.clone())?);
+    if config.watch {
+        if let Err(e) = watch(Arc::downgrade(&resolver), &config.certs) {
+            log::warn!(
+                "File watcher is not available, TLS certificates will not be reloaded: {}",
+                e
+            );
+        }
+    }
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.cert_resolver = resolver;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::config::ListenAddr;
+    use crate::core::net;
+    use async_std::net::TcpStream;
+    use async_tls::TlsConnector;
+    use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+
+    /// Writes a self-signed certificate for `name` and its key into `dir`.
+    fn self_signed(dir: &Path, name: &str) -> (rcgen::Certificate, CertFiles) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let files = CertFiles {
+            cert: dir.join(format!("{}.pem", name)),
+            key: dir.join(format!("{}.key", name)),
+        };
+        std::fs::write(&files.cert, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&files.key, cert.serialize_private_key_pem()).unwrap();
+        (cert, files)
+    }
+
+    /// Connects trusting only `cert` and asking for `name`, then echoes `ping` through the server.
+    async fn handshake(addr: std::net::SocketAddr, name: &str, cert: &rcgen::Certificate) -> bool {
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let mut stream = match connector.connect(name, tcp).await {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.is_ok() && &buf == b"ping"
+    }
+
+    #[test]
+    fn test_tls_sni_and_reload() {
+        let dir = std::env::temp_dir().join(format!("laputa-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (default_cert, default_files) = self_signed(&dir, "localhost");
+        let (other_cert, other_files) = self_signed(&dir, "other.test");
+        let config = TlsConfig::new(&default_files.cert, &default_files.key)
+            .sni_cert(&other_files.cert, &other_files.key)
+            .watch(false);
+        let resolver = Arc::new(CertResolver::new(config.certs.clone()).unwrap());
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config.cert_resolver = resolver.clone();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        async_std::task::block_on(async {
+            let addrs = [ListenAddr::Tls("127.0.0.1:0".to_string())];
+            let (bound, mut incoming) = net::bind_all(&addrs, Some(acceptor)).await.unwrap();
+            let addr = bound[0].tcp().unwrap();
+            async_std::task::spawn(async move {
+                while let Some(Ok(mut conn)) = incoming.next().await {
+                    async_std::task::spawn(async move {
+                        let mut buf = [0u8; 4];
+                        if conn.read_exact(&mut buf).await.is_ok() {
+                            let _ = conn.write_all(&buf).await;
+                        }
+                    });
+                }
+            });
+
+            assert!(handshake(addr, "localhost", &default_cert).await);
+            assert!(handshake(addr, "other.test", &other_cert).await);
+            assert!(!handshake(addr, "localhost", &other_cert).await);
+
+            let (new_cert, _) = self_signed(&dir, "other.test");
+            resolver.reload();
+            assert!(handshake(addr, "other.test", &new_cert).await);
+            assert!(!handshake(addr, "other.test", &other_cert).await);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_tls_watch_reload() {
+        let dir = std::env::temp_dir().join(format!("laputa-tls-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (old_cert, files) = self_signed(&dir, "localhost");
+        let acceptor = acceptor(&TlsConfig::new(&files.cert, &files.key)).unwrap();
+
+        async_std::task::block_on(async {
+            let addrs = [ListenAddr::Tls("127.0.0.1:0".to_string())];
+            let (bound, mut incoming) = net::bind_all(&addrs, Some(acceptor)).await.unwrap();
+            let addr = bound[0].tcp().unwrap();
+            async_std::task::spawn(async move {
+                while let Some(Ok(mut conn)) = incoming.next().await {
+                    async_std::task::spawn(async move {
+                        let mut buf = [0u8; 4];
+                        if conn.read_exact(&mut buf).await.is_ok() {
+                            let _ = conn.write_all(&buf).await;
+                        }
+                    });
+                }
+            });
+            assert!(handshake(addr, "localhost", &old_cert).await);
+
+            // the files are rewritten on disk, the watcher swaps in the new key
+            let (new_cert, _) = self_signed(&dir, "localhost");
+            let mut reloaded = false;
+            for _ in 0..100 {
+                if handshake(addr, "localhost", &new_cert).await {
+                    reloaded = true;
+                    break;
+                }
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+            assert!(reloaded, "certificate is not reloaded by the watcher");
+            assert!(!handshake(addr, "localhost", &old_cert).await);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::common::StrErrResult;
+use crate::config::HttpClientConfig;
+use bytes::Bytes;
+use lazy_static::*;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use url::Url;
+
+const MAX_REDIRECTS: usize = 10;
+
+lazy_static! {
+    /// reqwest needs a tokio runtime, its futures are run there instead of on the async-std
+    /// executor of the server. The runtime lives as long as the process.
+    static ref RUNTIME: Handle = {
+        let runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(2)
+            .thread_name("http-client")
+            .enable_all()
+            .build()
+            .expect("Cannot start the http client runtime");
+        let handle = runtime.handle().clone();
+        std::mem::forget(runtime);
+        handle
+    };
+}
+
+/// Runs a future of reqwest on the http client runtime.
+pub(crate) async fn spawn<F>(fut: F) -> StrErrResult<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    RUNTIME.spawn(fut).await.map_err(|e| e.to_string())
+}
+
+/// Whether `url` matches one of the allowed host patterns, see `HttpClientConfig::allow_host`.
+fn host_allowed(patterns: &[String], url: &Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) => host.to_lowercase(),
+        None => return false,
+    };
+    let with_port = url
+        .port_or_known_default()
+        .map(|port| format!("{}:{}", host, port));
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        if pattern == "*" || pattern == host || Some(&pattern) == with_port.as_ref() {
+            return true;
+        }
+        if pattern.starts_with("*.") {
+            return host.ends_with(&pattern[1..]);
+        }
+        false
+    })
+}
+
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) url: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Bytes,
+}
+
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    pub(crate) status_text: String,
+    pub(crate) url: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: HttpBody,
+}
+
+/// Body of a response, read chunk by chunk as it arrives.
+pub(crate) struct HttpBody(reqwest::Response);
+
+impl HttpBody {
+    /// Reads the next chunk, `None` at the end of the body. Runs on the http client runtime.
+    pub(crate) async fn chunk(mut self) -> (Self, StrErrResult<Option<Bytes>>) {
+        let chunk = self.0.chunk().await.map_err(|e| e.to_string());
+        (self, chunk)
+    }
+
+    /// Reads the whole body. Runs on the http client runtime.
+    pub(crate) async fn bytes(self) -> StrErrResult<Bytes> {
+        self.0.bytes().await.map_err(|e| e.to_string())
+    }
+}
+
+/// Client of the outbound requests made by scripts, requests are only sent to allowed hosts.
+#[derive(Clone)]
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl HttpClient {
+    pub(crate) fn new(config: &HttpClientConfig) -> StrErrResult<Self> {
+        let allowed_hosts = Arc::new(config.allowed_hosts.clone());
+        let redirect_hosts = allowed_hosts.clone();
+        let policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("Too many redirects")
+            } else if host_allowed(&redirect_hosts, attempt.url()) {
+                attempt.follow()
+            } else {
+                let message = format!("Redirect to {} is not allowed", attempt.url());
+                attempt.error(message)
+            }
+        });
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .redirect(policy)
+            .build()
+            .map_err(|e| format!("Cannot create http client: {}", e))?;
+        Ok(HttpClient {
+            client,
+            allowed_hosts,
+        })
+    }
+
+    pub(crate) fn check_url(&self, url: &str) -> StrErrResult<Url> {
+        let url = Url::parse(url).map_err(|e| format!("Invalid url {}: {}", url, e))?;
+        match url.scheme() {
+            "http" | "https" => {}
+            scheme => return Err(format!("Unsupported url scheme {}", scheme)),
+        }
+        if !host_allowed(&self.allowed_hosts, &url) {
+            return Err(format!(
+                "Host {} is not allowed",
+                url.host_str().unwrap_or_default()
+            ));
+        }
+        Ok(url)
+    }
+
+    /// Checks the request and returns the future sending it, which has to be run with `spawn`.
+    pub(crate) fn send(
+        &self,
+        request: HttpRequest,
+    ) -> StrErrResult<impl Future<Output = StrErrResult<HttpResponse>> + Send + 'static> {
+        let url = self.check_url(&request.url)?;
+        let method = reqwest::Method::from_bytes(request.method.to_uppercase().as_bytes())
+            .map_err(|_| format!("Invalid method {}", request.method))?;
+        let mut builder = self.client.request(method, url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        if !request.body.is_empty() {
+            builder = builder.body(request.body);
+        }
+        Ok(async move {
+            let response = builder.send().await.map_err(|e| e.to_string())?;
+            let status = response.status();
+            let headers = response
+                .headers()
+                .iter()
+                .map(|(k, v)| {
+                    let value = String::from_utf8_lossy(v.as_bytes()).into_owned();
+                    (k.as_str().to_string(), value)
+                })
+                .collect();
+            Ok(HttpResponse {
+                status: status.as_u16(),
+                status_text: status.canonical_reason().unwrap_or("").to_string(),
+                url: response.url().to_string(),
+                headers,
+                body: HttpBody(response),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_host_allowed() {
+        let patterns = vec![
+            "api.example.com".to_string(),
+            "localhost:8080".to_string(),
+            "*.test.org".to_string(),
+        ];
+        let allowed = |url: &str| host_allowed(&patterns, &Url::parse(url).unwrap());
+        assert!(allowed("https://api.example.com/v1"));
+        assert!(allowed("http://API.example.com:8000/"));
+        assert!(!allowed("https://example.com/"));
+        assert!(allowed("http://localhost:8080/"));
+        assert!(!allowed("http://localhost:8081/"));
+        assert!(allowed("https://a.b.test.org/"));
+        assert!(!allowed("https://test.org/"));
+        assert!(host_allowed(
+            &["*".to_string()],
+            &Url::parse("http://any.host/").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            let response = "HTTP/1.1 201 Created\r\nX-Test: yes\r\nContent-Length: 5\r\n\r\nhello";
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        let client = HttpClient::new(&HttpClientConfig::new().allow_host("127.0.0.1")).unwrap();
+        let request = |url: String| HttpRequest {
+            method: "get".to_string(),
+            url,
+            headers: vec![],
+            body: Bytes::new(),
+        };
+        assert!(client
+            .send(request("http://localhost/".to_string()))
+            .is_err());
+
+        let send = client.send(request(format!("http://{}/", addr))).unwrap();
+        async_std::task::block_on(async {
+            let response = spawn(send).await.unwrap().unwrap();
+            assert_eq!(response.status, 201);
+            assert_eq!(response.status_text, "Created");
+            assert!(response
+                .headers
+                .contains(&("x-test".to_string(), "yes".to_string())));
+            let body = spawn(response.body.bytes()).await.unwrap().unwrap();
+            assert_eq!(&body[..], b"hello");
+        });
+    }
+}
@@ -1,14 +1,29 @@
 pub mod common;
+mod compression;
+pub mod config;
+mod core;
+mod http_client;
 mod inner_pages;
 mod logger_config;
+pub mod manifest;
+pub mod middleware;
 mod script;
 mod server;
 pub mod service;
+mod shutdown;
+mod static_files;
+
+pub use server::{BoundServer, Server};
+pub use shutdown::ShutdownHandle;
 
 pub fn new() -> server::Server {
+    with_config(config::ServerConfig::default())
+}
+
+pub fn with_config(config: config::ServerConfig) -> server::Server {
     logger_config::print_banner();
-    logger_config::init_log();
+    logger_config::init_log(config.log_level.as_deref());
 
     log::info!("Start to initialize server");
-    server::Server::new()
+    server::Server::new(config)
 }
@@ -17,14 +17,15 @@ pub fn print_banner() {
     println!("{}", BANNER.bright_blue());
 }
 
-pub fn init_log() {
+pub fn init_log(level: Option<&str>) {
     use chrono::Local;
     use colored::*;
     use log::Level::*;
     use std::io::Write;
 
-    let env = env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "debug");
-    env_logger::Builder::from_env(env)
+    let env = env_logger::Env::default()
+        .filter_or(env_logger::DEFAULT_FILTER_ENV, level.unwrap_or("debug"));
+    let result = env_logger::Builder::from_env(env)
         .format(|buf, record| {
             let level = match record.level() {
                 Error => Error.to_string().as_str().bright_red(),
@@ -72,7 +73,10 @@ pub fn init_log() {
                 thread = thread,
             )
         })
-        .init();
+        .try_init();
 
-    info!("Logger config initialized");
+    // Several servers may live in one process (e.g. in tests), only the first one sets the logger
+    if result.is_ok() {
+        info!("Logger config initialized");
+    }
 }
@@ -0,0 +1,16 @@
+use async_std::task;
+use laputa::common;
+use laputa::manifest::Manifest;
+
+static DEFAULT_MANIFEST: &str = "laputa.toml";
+
+fn main() -> common::BoxErrResult<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_MANIFEST.to_string());
+    let manifest = Manifest::from_file(&path)?;
+    task::block_on(async {
+        let server = manifest.build_server()?;
+        server.start().await
+    })
+}
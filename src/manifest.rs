@@ -0,0 +1,427 @@
+use crate::common::{BoxErrResult, ServiceState};
+use crate::config::{
+    EngineConfig, HttpClientConfig, Methods, RouteOptions, ServerConfig, TlsConfig,
+    DEFAULT_COMPRESSION_MIN_SIZE,
+};
+use crate::middleware::{AccessLog, BodyLimit, Cors, Middleware, RequestId, SecurityHeaders};
+use crate::server::Server;
+use crate::service::ScriptType;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tide::http::Method;
+
+static STATIC_TYPE: &str = "static";
+static ANY_METHOD: &str = "*";
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Manifest {
+    #[serde(default)]
+    pub listen: Vec<String>,
+    pub log_level: Option<String>,
+    pub script_timeout_ms: Option<u64>,
+    pub compression: Option<bool>,
+    pub compression_min_size: Option<usize>,
+    pub shutdown_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub dirs: Vec<String>,
+    #[serde(default)]
+    pub engines: HashMap<String, EngineEntry>,
+    pub http_client: Option<HttpClientEntry>,
+    pub tls: Option<TlsEntry>,
+    #[serde(default)]
+    pub middlewares: Vec<MiddlewareEntry>,
+    #[serde(default)]
+    pub routes: Vec<RouteEntry>,
+    /// Directory of the manifest file, relative paths in the manifest are resolved against it.
+    #[serde(skip)]
+    pub base_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct EngineEntry {
+    pub workers: Option<usize>,
+    pub dispatch: Option<String>,
+    pub memory_limit_mb: Option<usize>,
+    pub event_loop_timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HttpClientEntry {
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TlsEntry {
+    pub cert: String,
+    pub key: String,
+    #[serde(default)]
+    pub sni: Vec<CertEntry>,
+    pub watch: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CertEntry {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RouteEntry {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub file: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub mime: Option<String>,
+    #[serde(default)]
+    pub dedicated: bool,
+    pub timeout_ms: Option<u64>,
+}
+
+/// A built-in middleware, `type` is one of `request_id`, `access_log`, `cors`, `body_limit`
+/// and `security_headers`, the other fields are the options of that type.
+#[derive(Deserialize, Debug)]
+pub struct MiddlewareEntry {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub prefix: Option<String>,
+    pub trust_header: Option<bool>,
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    #[serde(default)]
+    pub allow_methods: Vec<String>,
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_secs: Option<u64>,
+    pub limit_bytes: Option<u64>,
+    pub content_security_policy: Option<String>,
+    pub hsts_max_age_secs: Option<u64>,
+}
+
+impl Manifest {
+    pub fn from_file(path: impl AsRef<Path>) -> BoxErrResult<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read manifest {}: {}", path.display(), e))?;
+        let mut manifest = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml(&content)?,
+            _ => Self::from_toml(&content)?,
+        };
+        manifest.base_dir = path.parent().map(Path::to_path_buf);
+        Ok(manifest)
+    }
+
+    pub fn from_toml(content: &str) -> BoxErrResult<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn from_yaml(content: &str) -> BoxErrResult<Self> {
+        Ok(serde_yaml::from_str(content)?)
+    }
+
+    pub fn server_config(&self) -> BoxErrResult<ServerConfig> {
+        let mut config = ServerConfig::new();
+        for addr in &self.listen {
+            config = config.listen(addr.as_str());
+        }
+        if let Some(level) = &self.log_level {
+            config = config.log_level(level.as_str());
+        }
+        if let Some(timeout) = self.script_timeout_ms {
+            config = config.script_timeout(Some(Duration::from_millis(timeout)));
+        }
+        if self.compression == Some(true)
+            || (self.compression.is_none() && self.compression_min_size.is_some())
+        {
+            let min_size = self
+                .compression_min_size
+                .unwrap_or(DEFAULT_COMPRESSION_MIN_SIZE);
+            config = config.compression(Some(min_size));
+        }
+        if let Some(timeout) = self.shutdown_timeout_ms {
+            config = config.shutdown_timeout(Duration::from_millis(timeout));
+        }
+        for proxy in &self.trusted_proxies {
+            if proxy == "unix" {
+                config = config.trust_unix_proxy(true);
+            } else {
+                let ip = proxy
+                    .parse()
+                    .map_err(|e| format!("Invalid trusted proxy {}: {}", proxy, e))?;
+                config = config.trusted_proxy(ip);
+            }
+        }
+        for (name, entry) in &self.engines {
+            let engine = entry.engine_config()?;
+            match ScriptType::from_str(name)? {
+                ScriptType::JavaScript => config = config.js_engine(engine),
+                ScriptType::Lua => config = config.lua_engine(engine),
+            }
+        }
+        if let Some(entry) = &self.http_client {
+            config = config.http_client(entry.http_client_config());
+        }
+        if let Some(entry) = &self.tls {
+            config = config.tls(entry.tls_config(self.base_dir.as_ref().map(PathBuf::as_path)));
+        }
+        Ok(config)
+    }
+
+    pub fn build_server(&self) -> BoxErrResult<Server> {
+        let mut server = crate::with_config(self.server_config()?);
+        for middleware in &self.middlewares {
+            middleware.register(&mut server)?;
+        }
+        let base_dir = self.base_dir.as_ref().map(PathBuf::as_path);
+        for dir in &self.dirs {
+            server.route_dir(&resolve_path(base_dir, dir))?;
+        }
+        for route in &self.routes {
+            route.register(&mut server, base_dir)?;
+        }
+        Ok(server)
+    }
+}
+
+/// Joins a relative `path` to the manifest directory, the working directory is used without it.
+fn resolve_path(base_dir: Option<&Path>, path: &str) -> String {
+    match base_dir {
+        Some(base_dir) if Path::new(path).is_relative() => {
+            base_dir.join(path).to_string_lossy().to_string()
+        }
+        _ => path.to_string(),
+    }
+}
+
+impl EngineEntry {
+    fn engine_config(&self) -> BoxErrResult<EngineConfig> {
+        let mut config = EngineConfig::new();
+        if let Some(workers) = self.workers {
+            config = config.workers(workers);
+        }
+        if let Some(dispatch) = &self.dispatch {
+            config = config.dispatch(dispatch.parse()?);
+        }
+        if let Some(limit) = self.memory_limit_mb {
+            config = config.memory_limit(limit * 1024 * 1024);
+        }
+        if let Some(timeout) = self.event_loop_timeout_ms {
+            config = config.event_loop_timeout(Duration::from_millis(timeout));
+        }
+        Ok(config)
+    }
+}
+
+impl HttpClientEntry {
+    fn http_client_config(&self) -> HttpClientConfig {
+        let mut config = HttpClientConfig::new();
+        for host in &self.allowed_hosts {
+            config = config.allow_host(host.as_str());
+        }
+        if let Some(timeout) = self.timeout_ms {
+            config = config.timeout(Duration::from_millis(timeout));
+        }
+        config
+    }
+}
+
+impl TlsEntry {
+    fn tls_config(&self, base_dir: Option<&Path>) -> TlsConfig {
+        let resolve = |path: &str| resolve_path(base_dir, path);
+        let mut config = TlsConfig::new(resolve(&self.cert), resolve(&self.key));
+        for entry in &self.sni {
+            config = config.sni_cert(resolve(&entry.cert), resolve(&entry.key));
+        }
+        if let Some(watch) = self.watch {
+            config = config.watch(watch);
+        }
+        config
+    }
+}
+
+impl MiddlewareEntry {
+    fn cors(&self) -> BoxErrResult<Cors> {
+        let mut cors = Cors::new();
+        for origin in &self.allow_origins {
+            cors = cors.allow_origin(origin.as_str());
+        }
+        if !self.allow_methods.is_empty() {
+            let mut methods = Vec::with_capacity(self.allow_methods.len());
+            for m in &self.allow_methods {
+                let method = Method::from_str(&m.to_uppercase())
+                    .map_err(|e| format!("Invalid method {} for cors: {}", m, e))?;
+                methods.push(method);
+            }
+            cors = cors.allow_methods(methods);
+        }
+        for name in &self.allow_headers {
+            cors = cors.allow_header(name.as_str());
+        }
+        for name in &self.expose_headers {
+            cors = cors.expose_header(name.as_str());
+        }
+        if let Some(allow) = self.allow_credentials {
+            cors = cors.allow_credentials(allow);
+        }
+        if let Some(max_age) = self.max_age_secs {
+            cors = cors.max_age(Duration::from_secs(max_age));
+        }
+        Ok(cors)
+    }
+
+    fn add(&self, server: &mut Server, middleware: impl Middleware<ServiceState>) {
+        match &self.prefix {
+            Some(prefix) => server.with_middleware_at(prefix, middleware),
+            None => server.with_middleware(middleware),
+        };
+    }
+
+    fn register(&self, server: &mut Server) -> BoxErrResult<()> {
+        match self.kind.to_lowercase().as_str() {
+            "request_id" => {
+                let trust = self.trust_header.unwrap_or(true);
+                self.add(server, RequestId::new().trust_header(trust));
+            }
+            "access_log" => self.add(server, AccessLog::new()),
+            "cors" => {
+                let cors = self.cors()?;
+                self.add(server, cors);
+            }
+            "body_limit" => {
+                let limit = self
+                    .limit_bytes
+                    .ok_or_else(|| "Middleware body_limit requires limit_bytes".to_string())?;
+                self.add(server, BodyLimit::new(limit));
+            }
+            "security_headers" => {
+                let headers = SecurityHeaders::new()
+                    .content_security_policy(self.content_security_policy.clone())
+                    .hsts(self.hsts_max_age_secs.map(Duration::from_secs));
+                self.add(server, headers);
+            }
+            kind => return Err(format!("Unknown middleware type {}", kind).into()),
+        }
+        Ok(())
+    }
+}
+
+impl RouteEntry {
+    fn methods(&self) -> BoxErrResult<Methods> {
+        if self.methods.is_empty() {
+            return Ok(Method::GET.into());
+        }
+        let mut methods = Vec::with_capacity(self.methods.len());
+        for m in &self.methods {
+            if m == ANY_METHOD || m.eq_ignore_ascii_case("any") {
+                return Ok(Methods::Any);
+            }
+            let method = Method::from_str(&m.to_uppercase())
+                .map_err(|e| format!("Invalid method {} for route {}: {}", m, self.path, e))?;
+            methods.push(method);
+        }
+        Ok(Methods::Set(methods))
+    }
+
+    fn mime(&self) -> BoxErrResult<mime::Mime> {
+        match &self.mime {
+            Some(m) => Ok(m
+                .parse()
+                .map_err(|e| format!("Invalid mime {} for route {}: {}", m, self.path, e))?),
+            None => Ok(mime_guess::from_path(&self.file).first_or_octet_stream()),
+        }
+    }
+
+    fn register(&self, server: &mut Server, base_dir: Option<&Path>) -> BoxErrResult<()> {
+        let methods = self.methods()?;
+        let file = resolve_path(base_dir, &self.file);
+        if self.kind.eq_ignore_ascii_case(STATIC_TYPE) && Path::new(&file).is_dir() {
+            server.serve_dir(&self.path, &file)?;
+        } else if self.kind.eq_ignore_ascii_case(STATIC_TYPE) {
+            server.route_static(methods, self.mime()?, &self.path, &file)?;
+        } else {
+            let script_type = ScriptType::from_str(&self.kind)?;
+            let mut options = RouteOptions::new().dedicated(self.dedicated);
+            if let Some(timeout) = self.timeout_ms {
+                options = options.timeout(Duration::from_millis(timeout));
+            }
+            server.route_script_with(script_type, methods, &self.path, &file, options)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let toml = r#"
+            listen = ["0.0.0.0:8080"]
+            log_level = "info"
+
+            [[middlewares]]
+            type = "cors"
+            prefix = "/api"
+            allow_origins = ["https://example.com"]
+            allow_methods = ["get", "put"]
+
+            [[middlewares]]
+            type = "body_limit"
+            limit_bytes = 1048576
+
+            [[routes]]
+            path = "js"
+            type = "javascript"
+            file = "deploy/ping_javascript.js"
+            methods = ["get", "post"]
+        "#;
+        let manifest = Manifest::from_toml(toml).unwrap();
+        assert_eq!(manifest.listen, vec!["0.0.0.0:8080".to_string()]);
+        assert_eq!(manifest.middlewares.len(), 2);
+        assert_eq!(manifest.middlewares[0].prefix, Some("/api".to_string()));
+        assert!(manifest.middlewares[0].cors().is_ok());
+        assert_eq!(manifest.middlewares[1].limit_bytes, Some(1048576));
+        assert_eq!(
+            manifest.routes[0].methods().unwrap(),
+            Methods::Set(vec![Method::GET, Method::POST])
+        );
+
+        let yaml = r#"
+            routes:
+              - path: static
+                type: static
+                file: deploy/ping_static.html
+              - path: lua
+                type: lua
+                file: deploy/ping_lua.lua
+                methods: ["*"]
+        "#;
+        let manifest = Manifest::from_yaml(yaml).unwrap();
+        assert_eq!(manifest.routes[0].methods().unwrap(), Method::GET.into());
+        assert_eq!(manifest.routes[0].mime().unwrap(), mime::TEXT_HTML);
+        assert_eq!(manifest.routes[1].methods().unwrap(), Methods::Any);
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let base = Path::new("/etc/laputa");
+        assert_eq!(
+            resolve_path(Some(base), "site/index.js"),
+            Path::new("/etc/laputa/site/index.js").to_string_lossy()
+        );
+        assert_eq!(resolve_path(Some(base), "/srv/site"), "/srv/site");
+        assert_eq!(resolve_path(None, "site"), "site");
+    }
+}
@@ -0,0 +1,728 @@
+use crate::compression;
+use crate::core::peer;
+use futures::future::{BoxFuture, TryFutureExt};
+use futures::io::AsyncRead;
+use futures::task::{Context, Poll};
+use http_service::{Body, HttpService};
+use lazy_static::*;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::http::{HeaderMap, Method};
+use tide::{Request, Response};
+
+pub use tide::{Middleware, Next};
+
+/// Header carrying the id of a request, from the client or a proxy, or generated.
+pub static REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+lazy_static! {
+    /// Distinguishes the generated ids of the processes behind the same proxy.
+    static ref PROCESS_TAG: u32 = {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        nanos ^ std::process::id()
+    };
+}
+static REQUEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Normalizes a route prefix into `/a/b`, the root is empty.
+fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", prefix)
+    }
+}
+
+/// Whether `path` is the normalized `prefix` or below it, `/api` does not cover `/apis`.
+fn in_scope(prefix: &str, path: &str) -> bool {
+    prefix.is_empty()
+        || (path.starts_with(prefix)
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/')))
+}
+
+/// Runs the wrapped middleware only for the requests under a route prefix.
+pub(crate) struct Scoped<M> {
+    prefix: String,
+    inner: M,
+}
+
+impl<M> Scoped<M> {
+    pub(crate) fn new(route_prefix: &str, inner: M) -> Self {
+        Scoped {
+            prefix: normalize_prefix(route_prefix),
+            inner,
+        }
+    }
+}
+
+impl<State, M> Middleware<State> for Scoped<M>
+where
+    State: Send + Sync + 'static,
+    M: Middleware<State>,
+{
+    fn handle<'a>(&'a self, req: Request<State>, next: Next<'a, State>) -> BoxFuture<'a, Response> {
+        if in_scope(&self.prefix, req.uri().path()) {
+            self.inner.handle(req, next)
+        } else {
+            next.run(req)
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Id of a request set by `RequestId`, for native handlers and the following middlewares.
+pub fn request_id<State>(req: &Request<State>) -> Option<&str> {
+    req.local::<RequestIdValue>().map(|id| id.0.as_str())
+}
+
+struct RequestIdValue(String);
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn next_request_id() -> String {
+    let count = REQUEST_COUNTER.fetch_add(1, Ordering::SeqCst);
+    format!("{:08x}-{:08x}", *PROCESS_TAG, count)
+}
+
+/// Tags each request with an id, kept from the `X-Request-Id` header of the request if it is
+/// valid, or generated, and sends it back in the response header.
+pub struct RequestId {
+    trust_header: bool,
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        RequestId { trust_header: true }
+    }
+
+    /// Whether the id sent by the client is kept, disable it when the server is not behind a
+    /// proxy setting the header.
+    pub fn trust_header(mut self, trust: bool) -> Self {
+        self.trust_header = trust;
+        self
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId::new()
+    }
+}
+
+impl<State: Send + Sync + 'static> Middleware<State> for RequestId {
+    fn handle<'a>(&'a self, req: Request<State>, next: Next<'a, State>) -> BoxFuture<'a, Response> {
+        let id = header(req.headers(), REQUEST_ID_HEADER)
+            .filter(|id| self.trust_header && valid_request_id(id))
+            .map(|id| id.to_string())
+            .unwrap_or_else(next_request_id);
+        let req = req.set_local(RequestIdValue(id.clone()));
+        Box::pin(async move { next.run(req).await.set_header(REQUEST_ID_HEADER, id) })
+    }
+}
+
+/// Logs a line for each request: client address, request line, status, time taken and the
+/// request id if `RequestId` runs before.
+pub struct AccessLog;
+
+impl AccessLog {
+    pub fn new() -> Self {
+        AccessLog
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog::new()
+    }
+}
+
+impl<State: Send + Sync + 'static> Middleware<State> for AccessLog {
+    fn handle<'a>(&'a self, req: Request<State>, next: Next<'a, State>) -> BoxFuture<'a, Response> {
+        let start = Instant::now();
+        let remote = peer::request_client_addr(&req).unwrap_or_else(|| "-".to_string());
+        let method = req.method().clone();
+        let uri = req.uri().to_string();
+        let version = req.version();
+        let id = request_id(&req).unwrap_or("-").to_string();
+        Box::pin(async move {
+            let resp = next.run(req).await;
+            log::info!(
+                "{} \"{} {} {:?}\" {} {:?} {}",
+                remote,
+                method,
+                uri,
+                version,
+                resp.status().as_u16(),
+                start.elapsed(),
+                id
+            );
+            resp
+        })
+    }
+}
+
+/// Answers CORS preflight requests and adds the CORS headers to the responses of allowed
+/// origins. Any origin is allowed unless some are set with `allow_origin`.
+pub struct Cors {
+    allow_origins: Vec<String>,
+    allow_methods: Vec<Method>,
+    allow_headers: Vec<String>,
+    expose_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Cors {
+            allow_origins: vec![],
+            allow_methods: vec![Method::GET, Method::HEAD, Method::POST],
+            allow_headers: vec![],
+            expose_headers: vec![],
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allows an origin like `https://example.com`, or `*` for any.
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        self.allow_origins
+            .push(origin.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Methods allowed in preflight requests, `GET`, `HEAD` and `POST` by default.
+    pub fn allow_methods(mut self, methods: Vec<Method>) -> Self {
+        self.allow_methods = methods;
+        self
+    }
+
+    /// Headers allowed in preflight requests, the requested ones are allowed if none is set.
+    pub fn allow_header(mut self, name: impl Into<String>) -> Self {
+        self.allow_headers.push(name.into());
+        self
+    }
+
+    /// Response headers readable by the scripts of the page.
+    pub fn expose_header(mut self, name: impl Into<String>) -> Self {
+        self.expose_headers.push(name.into());
+        self
+    }
+
+    /// Allows cookies and credentials, the origin is then sent back instead of `*`. Only the
+    /// origins set with `allow_origin` are allowed then, any origin is refused.
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.allow_credentials = allow;
+        self
+    }
+
+    /// How long browsers may cache the result of a preflight request.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Value of `Access-Control-Allow-Origin` for `origin`, `None` if it is not allowed.
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        let any = self.allow_origins.is_empty() || self.allow_origins.iter().any(|o| o == "*");
+        if any {
+            // credentials of any site must not be readable by any other site
+            if self.allow_credentials {
+                None
+            } else {
+                Some("*".to_string())
+            }
+        } else if self.allow_origins.iter().any(|o| o == origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// `Vary: Origin` is added by `VaryOrigin` to the responses with an origin sent back.
+    fn add_headers(&self, resp: Response, allow_origin: String) -> Response {
+        let mut resp = resp.set_header("access-control-allow-origin", allow_origin);
+        if self.allow_credentials {
+            resp = resp.set_header("access-control-allow-credentials", "true");
+        }
+        resp
+    }
+
+    fn preflight(&self, headers: &HeaderMap, allow_origin: String) -> Response {
+        let requested_headers = header(headers, "access-control-request-headers").unwrap_or("");
+        let allow_headers = if self.allow_headers.is_empty() {
+            requested_headers.to_string()
+        } else {
+            self.allow_headers.join(", ")
+        };
+        let allow_methods = self
+            .allow_methods
+            .iter()
+            .map(|m| m.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        let mut resp = self
+            .add_headers(Response::new(204), allow_origin)
+            .set_header("access-control-allow-methods", allow_methods);
+        if !allow_headers.is_empty() {
+            resp = resp.set_header("access-control-allow-headers", allow_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            resp = resp.set_header("access-control-max-age", max_age.as_secs().to_string());
+        }
+        resp
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new()
+    }
+}
+
+impl<State: Send + Sync + 'static> Middleware<State> for Cors {
+    fn handle<'a>(&'a self, req: Request<State>, next: Next<'a, State>) -> BoxFuture<'a, Response> {
+        let origin = match header(req.headers(), "origin") {
+            Some(origin) => origin.to_string(),
+            None => return next.run(req),
+        };
+        let allow_origin = self.allowed_origin(&origin);
+        let is_preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key("access-control-request-method");
+        if is_preflight {
+            let resp = match allow_origin {
+                Some(allow_origin) => self.preflight(req.headers(), allow_origin),
+                None => Response::new(403),
+            };
+            return Box::pin(futures::future::ready(resp));
+        }
+        Box::pin(async move {
+            let resp = next.run(req).await;
+            match allow_origin {
+                Some(allow_origin) => {
+                    let resp = self.add_headers(resp, allow_origin);
+                    if self.expose_headers.is_empty() {
+                        resp
+                    } else {
+                        resp.set_header(
+                            "access-control-expose-headers",
+                            self.expose_headers.join(", "),
+                        )
+                    }
+                }
+                None => resp,
+            }
+        })
+    }
+}
+
+/// Rejects requests with a body larger than a limit. A larger `Content-Length` is rejected
+/// at once, a body of unknown length fails to read once it is past the limit.
+pub struct BodyLimit {
+    limit: u64,
+}
+
+impl BodyLimit {
+    pub fn new(limit: u64) -> Self {
+        BodyLimit { limit }
+    }
+
+    /// Status of the rejected request, `None` if it is accepted.
+    fn check(&self, headers: &HeaderMap) -> Option<u16> {
+        match header(headers, "content-length") {
+            Some(length) => match length.trim().parse::<u64>() {
+                Ok(length) if length > self.limit => Some(413),
+                Ok(_) => None,
+                Err(_) => Some(400),
+            },
+            None => None,
+        }
+    }
+}
+
+impl<State: Send + Sync + 'static> Middleware<State> for BodyLimit {
+    fn handle<'a>(&'a self, req: Request<State>, next: Next<'a, State>) -> BoxFuture<'a, Response> {
+        match self.check(req.headers()) {
+            Some(status) => {
+                log::warn!(
+                    "Rejected request body of {} with status {}",
+                    req.uri(),
+                    status
+                );
+                Box::pin(futures::future::ready(Response::new(status)))
+            }
+            None => {
+                if let Some(cap) = req.local::<BodyCap>() {
+                    cap.lower(self.limit);
+                }
+                next.run(req)
+            }
+        }
+    }
+}
+
+/// Error of reading a request body past the limit of `BodyLimit`.
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Request body is larger than the limit")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// Whether reading a request body failed because of `BodyLimit`, answered with 413.
+pub fn body_too_large(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |e| e.is::<BodyTooLarge>())
+}
+
+/// Limit of the body of a request, in its extensions, lowered by `BodyLimit`.
+#[derive(Clone)]
+struct BodyCap(Arc<AtomicU64>);
+
+impl BodyCap {
+    fn lower(&self, limit: u64) {
+        if limit < self.0.load(Ordering::SeqCst) {
+            self.0.store(limit, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Request body failing with `BodyTooLarge` once more bytes than its cap are read.
+struct CappedBody {
+    inner: Body,
+    read: u64,
+    cap: BodyCap,
+}
+
+impl AsyncRead for CappedBody {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let body = self.get_mut();
+        let read = match Pin::new(&mut body.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) => read,
+            poll => return poll,
+        };
+        body.read += read as u64;
+        if body.read > body.cap.0.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                BodyTooLarge,
+            )));
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+/// Counts the bytes read from the request bodies, for `BodyLimit` to limit the bodies of
+/// unknown length.
+pub(crate) struct CapBodies<S> {
+    inner: S,
+}
+
+impl<S> CapBodies<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        CapBodies { inner }
+    }
+}
+
+impl<S: HttpService> HttpService for CapBodies<S> {
+    type Connection = S::Connection;
+    type ConnectionFuture = S::ConnectionFuture;
+    type ResponseFuture = S::ResponseFuture;
+
+    fn connect(&self) -> Self::ConnectionFuture {
+        self.inner.connect()
+    }
+
+    fn respond(
+        &self,
+        conn: &mut Self::Connection,
+        req: http_service::Request,
+    ) -> Self::ResponseFuture {
+        let cap = BodyCap(Arc::new(AtomicU64::new(u64::MAX)));
+        let (mut parts, body) = req.into_parts();
+        parts.extensions.insert(cap.clone());
+        let body = Body::from_reader(CappedBody {
+            inner: body,
+            read: 0,
+            cap,
+        });
+        self.inner
+            .respond(conn, http_service::Request::from_parts(parts, body))
+    }
+}
+
+/// Adds `Origin` to the `Vary` header of a response whose origin is sent back by `Cors`, the
+/// response varies with the origin of the request then.
+fn vary_origin(headers: &mut HeaderMap) {
+    let allow_origin = header(headers, "access-control-allow-origin");
+    if allow_origin.map_or(false, |origin| origin != "*") {
+        compression::append_vary(headers, "origin");
+    }
+}
+
+/// Completes the `Vary` header of the responses of `Cors`, all the values set by the routes
+/// and the other middlewares are only in sight of the whole response.
+pub(crate) struct VaryOrigin<S> {
+    inner: S,
+}
+
+impl<S> VaryOrigin<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        VaryOrigin { inner }
+    }
+}
+
+impl<S> HttpService for VaryOrigin<S>
+where
+    S: HttpService,
+    <S::ResponseFuture as futures::TryFuture>::Error: Send,
+{
+    type Connection = S::Connection;
+    type ConnectionFuture = S::ConnectionFuture;
+    type ResponseFuture = BoxFuture<
+        'static,
+        Result<http_service::Response, <S::ResponseFuture as futures::TryFuture>::Error>,
+    >;
+
+    fn connect(&self) -> Self::ConnectionFuture {
+        self.inner.connect()
+    }
+
+    fn respond(
+        &self,
+        conn: &mut Self::Connection,
+        req: http_service::Request,
+    ) -> Self::ResponseFuture {
+        let resp = self.inner.respond(conn, req).into_future();
+        Box::pin(async move {
+            let mut resp = resp.await?;
+            vary_origin(resp.headers_mut());
+            Ok(resp)
+        })
+    }
+}
+
+/// Adds headers hardening the responses against sniffing, framing and leaking referrers.
+/// Headers set to `None` are not sent, the ones of the middleware replace those of the route.
+pub struct SecurityHeaders {
+    content_type_options: bool,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    content_security_policy: Option<String>,
+    hsts: Option<Duration>,
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        SecurityHeaders {
+            content_type_options: true,
+            frame_options: Some("SAMEORIGIN".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            content_security_policy: None,
+            hsts: None,
+        }
+    }
+
+    /// Sends `X-Content-Type-Options: nosniff`, enabled by default.
+    pub fn content_type_options(mut self, enabled: bool) -> Self {
+        self.content_type_options = enabled;
+        self
+    }
+
+    /// `X-Frame-Options`, `SAMEORIGIN` by default.
+    pub fn frame_options(mut self, value: Option<String>) -> Self {
+        self.frame_options = value;
+        self
+    }
+
+    /// `Referrer-Policy`, `strict-origin-when-cross-origin` by default.
+    pub fn referrer_policy(mut self, value: Option<String>) -> Self {
+        self.referrer_policy = value;
+        self
+    }
+
+    /// `Content-Security-Policy`, not sent by default.
+    pub fn content_security_policy(mut self, value: Option<String>) -> Self {
+        self.content_security_policy = value;
+        self
+    }
+
+    /// `Strict-Transport-Security` with subdomains, only for servers reached by HTTPS.
+    pub fn hsts(mut self, max_age: Option<Duration>) -> Self {
+        self.hsts = max_age;
+        self
+    }
+
+    fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![];
+        if self.content_type_options {
+            headers.push(("x-content-type-options", "nosniff".to_string()));
+        }
+        if let Some(value) = &self.frame_options {
+            headers.push(("x-frame-options", value.clone()));
+        }
+        if let Some(value) = &self.referrer_policy {
+            headers.push(("referrer-policy", value.clone()));
+        }
+        if let Some(value) = &self.content_security_policy {
+            headers.push(("content-security-policy", value.clone()));
+        }
+        if let Some(max_age) = self.hsts {
+            let value = format!("max-age={}; includeSubDomains", max_age.as_secs());
+            headers.push(("strict-transport-security", value));
+        }
+        headers
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders::new()
+    }
+}
+
+impl<State: Send + Sync + 'static> Middleware<State> for SecurityHeaders {
+    fn handle<'a>(&'a self, req: Request<State>, next: Next<'a, State>) -> BoxFuture<'a, Response> {
+        Box::pin(async move {
+            let mut resp = next.run(req).await;
+            for (name, value) in self.headers() {
+                resp = resp.set_header(name, value);
+            }
+            resp
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_scope() {
+        let prefix = normalize_prefix("/api/");
+        assert_eq!(prefix, "/api");
+        assert!(in_scope(&prefix, "/api"));
+        assert!(in_scope(&prefix, "/api/users/1"));
+        assert!(!in_scope(&prefix, "/apis"));
+        assert!(!in_scope(&prefix, "/"));
+        assert!(in_scope(&normalize_prefix("/"), "/anything"));
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert!(valid_request_id("5f2b-a1"));
+        assert!(!valid_request_id(""));
+        assert!(!valid_request_id("with space"));
+        assert!(!valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+        assert_ne!(next_request_id(), next_request_id());
+    }
+
+    #[test]
+    fn test_cors_origin() {
+        let cors = Cors::new();
+        assert_eq!(cors.allowed_origin("https://a.test"), Some("*".to_string()));
+        let cors = Cors::new()
+            .allow_origin("https://a.test/")
+            .allow_credentials(true);
+        assert_eq!(
+            cors.allowed_origin("https://a.test"),
+            Some("https://a.test".to_string())
+        );
+        assert_eq!(cors.allowed_origin("https://b.test"), None);
+        // credentials are never allowed for any origin, no CORS headers are sent
+        let cors = Cors::new().allow_credentials(true);
+        assert_eq!(cors.allowed_origin("https://b.test"), None);
+        let cors = Cors::new().allow_origin("*").allow_credentials(true);
+        assert_eq!(cors.allowed_origin("https://b.test"), None);
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let limit = BodyLimit::new(10);
+        let mut headers = HeaderMap::new();
+        assert_eq!(limit.check(&headers), None);
+        headers.insert("content-length", "10".parse().unwrap());
+        assert_eq!(limit.check(&headers), None);
+        headers.insert("content-length", "11".parse().unwrap());
+        assert_eq!(limit.check(&headers), Some(413));
+        headers.insert("content-length", "ten".parse().unwrap());
+        assert_eq!(limit.check(&headers), Some(400));
+        headers.remove("content-length");
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        assert_eq!(limit.check(&headers), None);
+
+        // a chunked body fails to read past the limit
+        async_std::task::block_on(async {
+            use futures::io::AsyncReadExt;
+            let cap = BodyCap(Arc::new(AtomicU64::new(u64::MAX)));
+            cap.lower(10);
+            let mut body = CappedBody {
+                inner: Body::from("0123456789"),
+                read: 0,
+                cap: cap.clone(),
+            };
+            let mut buf = vec![];
+            assert!(body.read_to_end(&mut buf).await.is_ok());
+            let mut body = CappedBody {
+                inner: Body::from("0123456789a"),
+                read: 0,
+                cap,
+            };
+            let e = body.read_to_end(&mut vec![]).await.unwrap_err();
+            assert!(body_too_large(&e));
+        });
+    }
+
+    #[test]
+    fn test_vary_origin() {
+        let mut headers = HeaderMap::new();
+        headers.insert("access-control-allow-origin", "*".parse().unwrap());
+        vary_origin(&mut headers);
+        assert!(!headers.contains_key("vary"));
+
+        headers.insert(
+            "access-control-allow-origin",
+            "https://a.test".parse().unwrap(),
+        );
+        headers.append("vary", "accept-encoding".parse().unwrap());
+        headers.append("vary", "cookie".parse().unwrap());
+        vary_origin(&mut headers);
+        let vary: Vec<&str> = headers
+            .get_all("vary")
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(vary, vec!["accept-encoding", "cookie", "origin"]);
+
+        // an origin in any of the values is not added again
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "access-control-allow-origin",
+            "https://a.test".parse().unwrap(),
+        );
+        headers.append("vary", "accept-encoding".parse().unwrap());
+        headers.append("vary", "Cookie, Origin".parse().unwrap());
+        vary_origin(&mut headers);
+        assert_eq!(headers.get_all("vary").iter().count(), 2);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::common::{self, make_channel, BoxErrResult, Receiver, ScriptEvent, Sender};
+use crate::config::{Dispatch, EngineConfig};
+use futures::channel::oneshot;
+use futures::{SinkExt, StreamExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// Counts an event as running on a worker until the engine drops it.
+pub(crate) struct BusyGuard(Arc<AtomicUsize>);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Worker {
+    sender: Sender<ScriptEvent>,
+    busy: Arc<AtomicUsize>,
+}
+
+fn least_busy(workers: &[Worker], start: usize) -> usize {
+    let size = workers.len();
+    (0..size)
+        .map(|i| (start + i) % size)
+        .min_by_key(|&i| workers[i].busy.load(Ordering::SeqCst))
+        .unwrap_or(0)
+}
+
+/// Engine threads and the sender of their events.
+pub(crate) struct Engine {
+    pub(crate) sender: Sender<ScriptEvent>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Engine {
+    /// Closes the channel of events, the workers stop once the queued ones are handled.
+    pub(crate) fn close(&self) {
+        self.sender.close_channel();
+    }
+
+    /// Waits for the engine threads to stop, returns false if some are still running at the
+    /// deadline.
+    pub(crate) async fn join(self, deadline: Instant) -> bool {
+        let (tx, rx) = oneshot::channel();
+        let threads = self.threads;
+        let joiner = thread::Builder::new()
+            .name("engine-joiner".to_string())
+            .spawn(move || {
+                for handle in threads {
+                    let _ = handle.join();
+                }
+                let _ = tx.send(());
+            });
+        if let Err(e) = joiner {
+            log::error!("Cannot join engine threads: {}", e);
+            return false;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        async_std::future::timeout(remaining, rx).await.is_ok()
+    }
+}
+
+/// Starts `config.workers` engine threads running `run` and returns the sender of a dispatcher
+/// balancing events among them.
+pub(crate) fn start<F>(name: &str, config: &EngineConfig, run: F) -> BoxErrResult<Engine>
+where
+    F: Fn(Receiver<ScriptEvent>) + Send + Clone + 'static,
+{
+    let size = config.workers.max(1);
+    let mut workers = Vec::with_capacity(size);
+    let mut threads = Vec::with_capacity(size);
+    for i in 0..size {
+        let (send, rev) = make_channel::<ScriptEvent>();
+        let run = run.clone();
+        let thread_name = if size == 1 {
+            name.to_string()
+        } else {
+            format!("{}-{}", name, i)
+        };
+        let handle = thread::Builder::new()
+            .name(thread_name)
+            .spawn(move || run(rev))?;
+        threads.push(handle);
+        workers.push(Worker {
+            sender: send,
+            busy: Arc::new(AtomicUsize::new(0)),
+        });
+    }
+    if size == 1 {
+        let sender = workers.pop().unwrap().sender;
+        return Ok(Engine { sender, threads });
+    }
+
+    log::info!(
+        "Dispatching {} events to {} workers ({:?})",
+        name,
+        size,
+        config.dispatch
+    );
+    let (send, mut rev) = make_channel::<ScriptEvent>();
+    let dispatch = config.dispatch;
+    common::spawn_and_log_error(async move {
+        let mut next = 0usize;
+        while let Some(mut event) = rev.next().await {
+            let index = match dispatch {
+                Dispatch::RoundRobin => next % size,
+                Dispatch::LeastBusy => least_busy(&workers, next % size),
+            };
+            next = next.wrapping_add(1);
+            let worker = &mut workers[index];
+            worker.busy.fetch_add(1, Ordering::SeqCst);
+            event.guard = Some(BusyGuard(worker.busy.clone()));
+            worker.sender.send(event).await?;
+        }
+        Ok(())
+    });
+    Ok(Engine {
+        sender: send,
+        threads,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_busy() {
+        let workers = (0..3)
+            .map(|busy| Worker {
+                sender: make_channel().0,
+                busy: Arc::new(AtomicUsize::new(busy)),
+            })
+            .collect::<Vec<Worker>>();
+        assert_eq!(least_busy(&workers, 1), 0);
+        workers[0].busy.store(2, Ordering::SeqCst);
+        assert_eq!(least_busy(&workers, 0), 1);
+        // all workers are equally busy, pick the next one in turn
+        workers[1].busy.store(2, Ordering::SeqCst);
+        assert_eq!(least_busy(&workers, 2), 2);
+    }
+}
@@ -0,0 +1,101 @@
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+const WATCH_DELAY_MS: u64 = 200;
+
+/// Tracks files loaded by a script engine and reports the keys (module names, locations...)
+/// registered for the files changed on disk since the last check.
+pub(crate) struct FileWatcher {
+    watcher: Option<RecommendedWatcher>,
+    events: Receiver<DebouncedEvent>,
+    watched: HashMap<PathBuf, HashSet<String>>,
+}
+
+impl FileWatcher {
+    pub(crate) fn new() -> Self {
+        let (tx, events) = channel();
+        let watcher = match notify::watcher(tx, Duration::from_millis(WATCH_DELAY_MS)) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                log::warn!(
+                    "File watcher is not available, scripts will not be reloaded: {}",
+                    e
+                );
+                None
+            }
+        };
+        FileWatcher {
+            watcher,
+            events,
+            watched: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn watch(&mut self, file: impl AsRef<Path>, key: &str) {
+        let watcher = match self.watcher.as_mut() {
+            Some(w) => w,
+            None => return,
+        };
+        let path = match file.as_ref().canonicalize() {
+            Ok(p) => p,
+            Err(e) => {
+                log::warn!("Cannot watch {}: {}", file.as_ref().display(), e);
+                return;
+            }
+        };
+        if !self.watched.contains_key(&path) {
+            if let Err(e) = watcher.watch(&path, RecursiveMode::NonRecursive) {
+                log::warn!("Cannot watch {}: {}", path.display(), e);
+                return;
+            }
+            log::debug!("Watching file {}", path.display());
+        }
+        self.watched
+            .entry(path)
+            .or_insert_with(HashSet::new)
+            .insert(key.to_string());
+    }
+
+    /// Keys of the changed files. A changed file is no longer watched, it should be watched
+    /// again when it is reloaded (editors may replace the file instead of writing it).
+    pub(crate) fn changed(&mut self) -> HashSet<String> {
+        let mut paths = HashSet::new();
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                DebouncedEvent::Write(p)
+                | DebouncedEvent::Create(p)
+                | DebouncedEvent::Chmod(p)
+                | DebouncedEvent::Remove(p)
+                | DebouncedEvent::NoticeRemove(p) => {
+                    paths.insert(p);
+                }
+                DebouncedEvent::Rename(from, to) => {
+                    paths.insert(from);
+                    paths.insert(to);
+                }
+                DebouncedEvent::Rescan => {
+                    paths.extend(self.watched.keys().cloned());
+                }
+                DebouncedEvent::Error(e, p) => {
+                    log::warn!("Error in watching file {:?}: {}", p, e);
+                }
+                DebouncedEvent::NoticeWrite(_) => {}
+            }
+        }
+
+        let mut keys = HashSet::new();
+        for path in paths {
+            if let Some(path_keys) = self.watched.remove(&path) {
+                log::info!("File {} was changed", path.display());
+                if let Some(watcher) = self.watcher.as_mut() {
+                    let _ = watcher.unwatch(&path);
+                }
+                keys.extend(path_keys);
+            }
+        }
+        keys
+    }
+}
@@ -1,15 +1,20 @@
 use crate::common::{BoxErrResult, RequestData, ResponseData};
+use crate::script::js_engine::event_loop::Timer;
+use crate::script::js_engine::fetch;
+use crate::script::js_engine::js_isolate::Isolate;
 use bytes::Bytes;
 use log::*;
 use rusty_v8 as v8;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::c_void;
+use std::time::{Duration, Instant};
 
 pub(crate) fn make_response<'s>(
     scope: &mut impl v8::ToLocal<'s>,
     context: v8::Local<v8::Context>,
     source: v8::Local<'s, v8::Value>,
-) -> BoxErrResult<ResponseData> {
+) -> BoxErrResult<(ResponseData, Option<BodyStream>)> {
     if source.is_object() {
         let obj = source.to_object(scope).unwrap();
         let status_key = v8::String::new(scope, "status").unwrap();
@@ -24,27 +29,10 @@ pub(crate) fn make_response<'s>(
         if let Some(headers) = headers {
             if headers.is_object() {
                 let headers = headers.to_object(scope).unwrap();
-                let header_keys = headers.get_own_property_names(scope, context);
-                let header_keys_len = header_keys.length();
-                if header_keys_len > 0 {
-                    for i in 0..header_keys_len {
-                        let key_obj = header_keys.get_index(scope, context, i).unwrap();
-                        let key = key_obj.to_string(scope).unwrap();
-                        let key_str = key.to_rust_string_lossy(scope).trim().to_string();
-                        if !key_str.is_empty() {
-                            if let Some(value) = headers.get(scope, context, key_obj) {
-                                let value_str = value
-                                    .to_string(scope)
-                                    .unwrap()
-                                    .to_rust_string_lossy(scope)
-                                    .trim()
-                                    .to_string();
-                                if !value_str.is_empty() {
-                                    debug!("[JS]  Set header {}={}", key_str, value_str);
-                                    header_map.insert(key_str, value_str);
-                                }
-                            }
-                        }
+                for (key_str, value_str) in header_entries(scope, context, headers) {
+                    if !key_str.is_empty() && !value_str.is_empty() {
+                        debug!("[JS]  Set header {}={}", key_str, value_str);
+                        header_map.insert(key_str, value_str);
                     }
                 }
             }
@@ -53,37 +41,282 @@ pub(crate) fn make_response<'s>(
         let body_key = v8::String::new(scope, "body").unwrap();
         let body = obj.get(scope, context, body_key.into());
         if body.is_none() {
-            Ok(ResponseData {
-                status: 404,
-                headers: header_map,
-                body: Bytes::from("Script returns empty content"),
-            })
+            Ok((
+                ResponseData {
+                    status: 404,
+                    headers: header_map,
+                    body: Bytes::from("Script returns empty content"),
+                },
+                None,
+            ))
         } else {
-            let body_str = body
-                .unwrap()
-                .to_string(scope)
-                .unwrap()
-                .to_rust_string_lossy(scope);
-            Ok(ResponseData {
-                status,
-                headers: header_map,
-                body: Bytes::from(body_str),
-            })
+            let body = body.unwrap();
+            if let Some((reader, next)) = stream_reader(scope, context, body) {
+                let head = ResponseData {
+                    status,
+                    headers: header_map,
+                    body: Bytes::new(),
+                };
+                return Ok((head, Some(BodyStream::new(scope, reader, next))));
+            }
+            Ok((
+                ResponseData {
+                    status,
+                    headers: header_map,
+                    body: if body.is_null() || body.is_undefined() {
+                        Bytes::new()
+                    } else {
+                        body_bytes(scope, body)
+                    },
+                },
+                None,
+            ))
         }
     } else if source.is_string() {
         let eval_str = source.to_string(scope).unwrap().to_rust_string_lossy(scope);
-        Ok(ResponseData {
-            status: 200,
-            headers: HashMap::new(),
-            body: Bytes::from(eval_str),
-        })
+        Ok((
+            ResponseData {
+                status: 200,
+                headers: HashMap::new(),
+                body: Bytes::from(eval_str),
+            },
+            None,
+        ))
     } else {
-        Ok(ResponseData {
-            status: 404,
-            headers: HashMap::new(),
-            body: Bytes::from("Script returns empty content"),
-        })
+        Ok((
+            ResponseData {
+                status: 404,
+                headers: HashMap::new(),
+                body: Bytes::from("Script returns empty content"),
+            },
+            None,
+        ))
+    }
+}
+
+/// Trimmed name and value pairs of response headers, a `Headers` object or a plain object.
+fn header_entries<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    headers: v8::Local<'s, v8::Object>,
+) -> Vec<(String, String)> {
+    let mut entries = vec![];
+    if let Some(entries_fn) = get_function(scope, context, headers, "entries") {
+        let iter = entries_fn
+            .call(scope, context, headers.into(), &[])
+            .and_then(|iter| iter.to_object(scope));
+        let next = iter.and_then(|iter| get_function(scope, context, iter, "next"));
+        if let (Some(iter), Some(next)) = (iter, next) {
+            let done_key = v8::String::new(scope, "done").unwrap();
+            let value_key = v8::String::new(scope, "value").unwrap();
+            while let Some(result) = next
+                .call(scope, context, iter.into(), &[])
+                .and_then(|result| result.to_object(scope))
+            {
+                let done = result.get(scope, context, done_key.into());
+                if done.map(|done| done.is_true()).unwrap_or(true) {
+                    break;
+                }
+                let pair = result
+                    .get(scope, context, value_key.into())
+                    .and_then(|pair| v8::Local::<v8::Array>::try_from(pair).ok());
+                if let Some(pair) = pair {
+                    if let (Some(k), Some(v)) = (
+                        pair.get_index(scope, context, 0),
+                        pair.get_index(scope, context, 1),
+                    ) {
+                        entries.push((trimmed_string(scope, k), trimmed_string(scope, v)));
+                    }
+                }
+            }
+        }
+        return entries;
+    }
+    let header_keys = headers.get_own_property_names(scope, context);
+    for i in 0..header_keys.length() {
+        let key_obj = header_keys.get_index(scope, context, i).unwrap();
+        if let Some(value) = headers.get(scope, context, key_obj) {
+            entries.push((trimmed_string(scope, key_obj), trimmed_string(scope, value)));
+        }
+    }
+    entries
+}
+
+fn trimmed_string<'s>(scope: &mut impl v8::ToLocal<'s>, value: v8::Local<'s, v8::Value>) -> String {
+    value
+        .to_string(scope)
+        .map(|s| s.to_rust_string_lossy(scope).trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Bytes of a response body, `Uint8Array` (or any other view) and `ArrayBuffer` bodies are
+/// copied as is, other values are converted to string.
+fn body_bytes<'s>(scope: &mut impl v8::ToLocal<'s>, body: v8::Local<'s, v8::Value>) -> Bytes {
+    if body.is_array_buffer_view() {
+        let view = v8::Local::<v8::ArrayBufferView>::try_from(body).unwrap();
+        return view_bytes(view);
+    }
+    if body.is_array_buffer() {
+        let buffer = v8::Local::<v8::ArrayBuffer>::try_from(body).unwrap();
+        let len = buffer.byte_length();
+        if let Some(view) = v8::Uint8Array::new(buffer, 0, len) {
+            return view_bytes(view.into());
+        }
+    }
+    Bytes::from(body.to_string(scope).unwrap().to_rust_string_lossy(scope))
+}
+
+fn get_function<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    obj: v8::Local<'s, v8::Object>,
+    name: &str,
+) -> Option<v8::Local<'s, v8::Function>> {
+    let key = v8::String::new(scope, name)?;
+    let value = obj.get(scope, context, key.into())?;
+    v8::Local::<v8::Function>::try_from(value).ok()
+}
+
+/// The reader and its `next` function of a streaming body: an async iterator (e.g. an async
+/// generator) or a `ReadableStream` like object with `getReader()`.
+fn stream_reader<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    body: v8::Local<'s, v8::Value>,
+) -> Option<(v8::Local<'s, v8::Object>, v8::Local<'s, v8::Function>)> {
+    if !body.is_object() || body.is_array_buffer_view() || body.is_array_buffer() {
+        return None;
+    }
+    let obj = body.to_object(scope)?;
+    let iter_key = v8::Symbol::get_async_iterator(scope);
+    if let Some(value) = obj.get(scope, context, iter_key.into()) {
+        if let Ok(iter_fn) = v8::Local::<v8::Function>::try_from(value) {
+            let iter = iter_fn
+                .call(scope, context, obj.into(), &[])?
+                .to_object(scope)?;
+            let next = get_function(scope, context, iter, "next")?;
+            return Some((iter, next));
+        }
+    }
+    let get_reader = get_function(scope, context, obj, "getReader")?;
+    let reader = get_reader
+        .call(scope, context, obj.into(), &[])?
+        .to_object(scope)?;
+    let read = get_function(scope, context, reader, "read")?;
+    Some((reader, read))
+}
+
+/// Value of a promise, settled by running the pending microtasks, a rejected promise is an error
+/// with the rejection reason. Other values are returned as is.
+pub(crate) fn settle<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    value: v8::Local<'s, v8::Value>,
+) -> BoxErrResult<v8::Local<'s, v8::Value>> {
+    if !value.is_promise() {
+        return Ok(value);
+    }
+    let promise = v8::Local::<v8::Promise>::try_from(value).unwrap();
+    scope.isolate().run_microtasks();
+    match promise.state() {
+        v8::PromiseState::Fulfilled => Ok(promise.result(scope)),
+        v8::PromiseState::Rejected => {
+            let reason = promise.result(scope);
+            Err(value_message(scope, reason).into())
+        }
+        v8::PromiseState::Pending => Err("Promise is pending and cannot be settled".into()),
+    }
+}
+
+/// Message of a thrown or rejected value, e.g. `Error: message` for an error object.
+pub(crate) fn value_message<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    value: v8::Local<'s, v8::Value>,
+) -> String {
+    value
+        .to_string(scope)
+        .map(|s| s.to_rust_string_lossy(scope))
+        .unwrap_or_else(|| "Unknown error".to_string())
+}
+
+/// A streaming response body, read chunk by chunk while the event loop runs.
+pub(crate) struct BodyStream {
+    reader: v8::Global<v8::Object>,
+    next: v8::Global<v8::Function>,
+}
+
+impl BodyStream {
+    fn new<'s>(
+        scope: &mut impl v8::ToLocal<'s>,
+        reader: v8::Local<'s, v8::Object>,
+        next: v8::Local<'s, v8::Function>,
+    ) -> Self {
+        let mut stream = BodyStream {
+            reader: v8::Global::new(),
+            next: v8::Global::new(),
+        };
+        stream.reader.set(scope, reader);
+        stream.next.set(scope, next);
+        stream
+    }
+
+    /// Starts reading the next chunk, returns the result of `next()`, usually a promise.
+    pub(crate) fn read<'s>(
+        &self,
+        scope: &mut impl v8::ToLocal<'s>,
+        context: v8::Local<v8::Context>,
+    ) -> BoxErrResult<v8::Local<'s, v8::Value>> {
+        let reader = self.reader.get(scope).unwrap();
+        let next = self.next.get(scope).unwrap();
+        Ok(next
+            .call(scope, context, reader.into(), &[])
+            .ok_or("Error in reading body stream")?)
+    }
+
+    pub(crate) fn reset<'s>(&mut self, scope: &mut impl v8::ToLocal<'s>) {
+        self.reader.reset(scope);
+        self.next.reset(scope);
+    }
+}
+
+/// Chunk of a settled `next()` result of a body stream, `None` when the stream is done.
+pub(crate) fn stream_chunk<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    result: v8::Local<'s, v8::Value>,
+) -> BoxErrResult<Option<Bytes>> {
+    let result = settle(scope, result)?
+        .to_object(scope)
+        .ok_or("Body stream returns a non-object result")?;
+    let done_key = v8::String::new(scope, "done").unwrap();
+    let value_key = v8::String::new(scope, "value").unwrap();
+    let done = result
+        .get(scope, context, done_key.into())
+        .map(|done| done.is_true())
+        .unwrap_or(true);
+    if done {
+        return Ok(None);
     }
+    match result.get(scope, context, value_key.into()) {
+        Some(value) if !value.is_undefined() => Ok(Some(body_bytes(scope, value))),
+        _ => Ok(Some(Bytes::new())),
+    }
+}
+
+pub(crate) fn view_bytes(view: v8::Local<v8::ArrayBufferView>) -> Bytes {
+    let mut buf = vec![0u8; view.byte_length()];
+    view.copy_contents(&mut buf);
+    Bytes::from(buf)
+}
+
+/// Copies bytes to a new `Uint8Array`.
+pub(crate) fn uint8_array<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    bytes: &[u8],
+) -> v8::Local<'s, v8::Uint8Array> {
+    let store =
+        v8::ArrayBuffer::new_backing_store_from_boxed_slice(bytes.to_vec().into_boxed_slice());
+    let buffer = v8::ArrayBuffer::with_backing_store(scope, &store.make_shared());
+    v8::Uint8Array::new(buffer, 0, bytes.len()).unwrap()
 }
 
 fn uri_accessor(
@@ -122,11 +355,149 @@ fn body_accessor(
     let scope = hs.enter();
     let v8_isolate = scope.isolate();
     let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
-    rv.set(
-        v8::String::new(scope, &String::from_utf8(request.body.to_vec()).unwrap())
-            .unwrap()
-            .into(),
-    )
+    // invalid utf-8 is replaced, binary bodies are read with `request.bodyBytes`
+    let body = String::from_utf8_lossy(&request.body);
+    if let Some(body) = v8::String::new(scope, &body) {
+        rv.set(body.into());
+    }
+}
+
+fn body_bytes_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    rv.set(uint8_array(scope, &request.body).into());
+}
+
+fn string_object<'s, 'a>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    entries: impl Iterator<Item = (&'a String, &'a String)>,
+) -> v8::Local<'s, v8::Object> {
+    let obj = v8::Object::new(scope);
+    for (k, v) in entries {
+        let key = v8::String::new(scope, k).unwrap();
+        let value = v8::String::new(scope, v).unwrap();
+        obj.set(context, key.into(), value.into());
+    }
+    obj
+}
+
+fn params_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let context = scope.get_current_context().unwrap();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    let params = string_object(scope, context, request.params.iter());
+    rv.set(params.into());
+}
+
+fn method_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    rv.set(v8::String::new(scope, &request.method).unwrap().into());
+}
+
+fn path_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    rv.set(v8::String::new(scope, &request.path).unwrap().into());
+}
+
+fn remote_addr_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    match &request.remote_addr {
+        Some(addr) => rv.set(v8::String::new(scope, addr).unwrap().into()),
+        None => rv.set(v8::null(scope).into()),
+    }
+}
+
+fn http_version_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    rv.set(v8::String::new(scope, &request.version).unwrap().into());
+}
+
+/// `request.searchParams`, maps each query name to the array of all its values.
+fn search_params_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let context = scope.get_current_context().unwrap();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    let params = v8::Object::new(scope);
+    for (k, values) in request.search_params() {
+        let key = v8::String::new(scope, &k).unwrap();
+        let values: Vec<v8::Local<v8::Value>> = values
+            .iter()
+            .map(|v| v8::String::new(scope, v).unwrap().into())
+            .collect();
+        let values = v8::Array::new_with_elements(scope, &values);
+        params.set(context, key.into(), values.into());
+    }
+    rv.set(params.into());
+}
+
+fn cookies_accessor(
+    scope: v8::PropertyCallbackScope,
+    _name: v8::Local<v8::Name>,
+    _args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let context = scope.get_current_context().unwrap();
+    let v8_isolate = scope.isolate();
+    let request = unsafe { &*(v8_isolate.get_data(1) as *mut RequestData) };
+    let cookies = request.cookies();
+    let cookies = string_object(scope, context, cookies.iter().map(|(k, v)| (k, v)));
+    rv.set(cookies.into());
 }
 
 fn headers_accessor(
@@ -175,12 +546,28 @@ pub(crate) fn make_request<'s>(
     let obj_name = v8::String::new(scope, "request").unwrap();
     let headers_name = v8::String::new(scope, "header").unwrap();
     let body_name = v8::String::new(scope, "body").unwrap();
+    let body_bytes_name = v8::String::new(scope, "bodyBytes").unwrap();
     let uri_name = v8::String::new(scope, "uri").unwrap();
     let query_name = v8::String::new(scope, "query").unwrap();
+    let params_name = v8::String::new(scope, "params").unwrap();
+    let method_name = v8::String::new(scope, "method").unwrap();
+    let path_name = v8::String::new(scope, "path").unwrap();
+    let remote_addr_name = v8::String::new(scope, "remoteAddr").unwrap();
+    let http_version_name = v8::String::new(scope, "httpVersion").unwrap();
+    let search_params_name = v8::String::new(scope, "searchParams").unwrap();
+    let cookies_name = v8::String::new(scope, "cookies").unwrap();
     let mut request_temp = v8::ObjectTemplate::new(scope);
     request_temp.set_accessor(uri_name, uri_accessor);
     request_temp.set_accessor(query_name, query_accessor);
+    request_temp.set_accessor(params_name, params_accessor);
+    request_temp.set_accessor(method_name, method_accessor);
+    request_temp.set_accessor(path_name, path_accessor);
+    request_temp.set_accessor(remote_addr_name, remote_addr_accessor);
+    request_temp.set_accessor(http_version_name, http_version_accessor);
+    request_temp.set_accessor(search_params_name, search_params_accessor);
+    request_temp.set_accessor(cookies_name, cookies_accessor);
     request_temp.set_accessor(body_name, body_accessor);
+    request_temp.set_accessor(body_bytes_name, body_bytes_accessor);
     let headers_func = v8::FunctionTemplate::new(scope, headers_accessor);
     request_temp.set_with_attr(
         headers_name.into(),
@@ -226,9 +613,118 @@ pub(crate) fn init_context<'s>(scope: &mut impl v8::ToLocal<'s>) -> v8::Local<'s
     let console_instance = console_obj.new_instance(scope, context).unwrap();
     global.set(context, console_key.into(), console_instance.into());
 
+    // timers and microtasks, run by the event loop of the isolate
+    set_function(scope, context, global, "setTimeout", js_set_timeout);
+    set_function(scope, context, global, "setInterval", js_set_interval);
+    set_function(scope, context, global, "clearTimeout", js_clear_timer);
+    set_function(scope, context, global, "clearInterval", js_clear_timer);
+    set_function(scope, context, global, "queueMicrotask", js_queue_microtask);
+    fetch::init(scope, context, global);
+
     scope.escape(context)
 }
 
+pub(crate) fn set_function<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    obj: v8::Local<v8::Object>,
+    name: &str,
+    callback: impl v8::MapFnTo<v8::FunctionCallback>,
+) {
+    let key = v8::String::new(scope, name).unwrap();
+    let mut template = v8::FunctionTemplate::new(scope, callback);
+    let function = template.get_function(scope, context).unwrap();
+    obj.set(context, key.into(), function.into());
+}
+
+pub(crate) fn throw_type_error<'s>(scope: &mut impl v8::ToLocal<'s>, message: &str) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = v8::Exception::type_error(scope, message);
+    scope.isolate().throw_exception(exception);
+}
+
+fn set_timer(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+    repeat: bool,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let callback = match v8::Local::<v8::Function>::try_from(args.get(0)) {
+        Ok(callback) => callback,
+        Err(_) => return throw_type_error(scope, "Timer callback must be a function"),
+    };
+    let delay = args.get(1).integer_value(scope).unwrap_or(0).max(0) as u64;
+    let delay = Duration::from_millis(delay);
+    let mut timer = Timer {
+        callback: v8::Global::new(),
+        args: vec![],
+        due: Instant::now() + delay,
+        interval: if repeat {
+            Some(delay.max(Duration::from_millis(1)))
+        } else {
+            None
+        },
+    };
+    timer.callback.set(scope, callback);
+    for i in 2..args.length() {
+        let mut arg = v8::Global::<v8::Value>::new();
+        arg.set(scope, args.get(i));
+        timer.args.push(arg);
+    }
+
+    let isolate = unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+    let id = isolate.timers.add(timer);
+    rv.set(v8::Integer::new_from_unsigned(scope, id).into());
+}
+
+fn js_set_timeout(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    rv: v8::ReturnValue,
+) {
+    set_timer(scope, args, rv, false)
+}
+
+fn js_set_interval(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    rv: v8::ReturnValue,
+) {
+    set_timer(scope, args, rv, true)
+}
+
+fn js_clear_timer(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let id = args.get(0).integer_value(scope).unwrap_or(0);
+    if id <= 0 || id > u32::max_value() as i64 {
+        return;
+    }
+    let isolate = unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+    if let Some(mut timer) = isolate.timers.remove(id as u32) {
+        timer.reset(scope);
+    }
+}
+
+fn js_queue_microtask(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    match v8::Local::<v8::Function>::try_from(args.get(0)) {
+        Ok(callback) => scope.isolate().enqueue_microtask(callback),
+        Err(_) => throw_type_error(scope, "Microtask must be a function"),
+    }
+}
+
 pub(crate) fn js_log(
     scope: v8::FunctionCallbackScope,
     args: v8::FunctionCallbackArguments,
@@ -0,0 +1,67 @@
+use rusty_v8 as v8;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub(crate) struct Timer {
+    pub(crate) callback: v8::Global<v8::Function>,
+    pub(crate) args: Vec<v8::Global<v8::Value>>,
+    pub(crate) due: Instant,
+    pub(crate) interval: Option<Duration>,
+}
+
+impl Timer {
+    pub(crate) fn reset<'s>(&mut self, scope: &mut impl v8::ToLocal<'s>) {
+        self.callback.reset(scope);
+        for arg in self.args.iter_mut() {
+            arg.reset(scope);
+        }
+    }
+}
+
+/// Timers of `setTimeout` and `setInterval` pending in an isolate.
+#[derive(Default)]
+pub(crate) struct Timers {
+    next_id: u32,
+    timers: HashMap<u32, Timer>,
+}
+
+impl Timers {
+    pub(crate) fn add(&mut self, timer: Timer) -> u32 {
+        // ids start from 1 so that a timer id is always truthy in javascript
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.timers.insert(self.next_id, timer);
+        self.next_id
+    }
+
+    pub(crate) fn get_mut(&mut self, id: u32) -> Option<&mut Timer> {
+        self.timers.get_mut(&id)
+    }
+
+    pub(crate) fn remove(&mut self, id: u32) -> Option<Timer> {
+        self.timers.remove(&id)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.timers.values().map(|t| t.due).min()
+    }
+
+    /// Ids of the timers due at `now`, in the order they have to run.
+    pub(crate) fn due(&self, now: Instant) -> Vec<u32> {
+        let mut due = self
+            .timers
+            .iter()
+            .filter(|(_, t)| t.due <= now)
+            .map(|(id, t)| (t.due, *id))
+            .collect::<Vec<(Instant, u32)>>();
+        due.sort();
+        due.into_iter().map(|(_, id)| id).collect()
+    }
+
+    pub(crate) fn drain(&mut self) -> Vec<Timer> {
+        self.timers.drain().map(|(_, t)| t).collect()
+    }
+}
@@ -0,0 +1,472 @@
+// Fetch API of the javascript runtime, backed by the native `ops` of the isolate:
+// fetch, Headers, Request, Response, ReadableStream, AbortController and AbortSignal.
+(function (ops, global) {
+    "use strict";
+
+    class DOMException extends Error {
+        constructor(message = "", name = "Error") {
+            super(message);
+            this.name = name;
+        }
+    }
+
+    function abortError() {
+        return new DOMException("The operation was aborted", "AbortError");
+    }
+
+    function toBytes(chunk) {
+        if (chunk instanceof Uint8Array) {
+            return chunk;
+        }
+        if (ArrayBuffer.isView(chunk)) {
+            return new Uint8Array(chunk.buffer, chunk.byteOffset, chunk.byteLength);
+        }
+        if (chunk instanceof ArrayBuffer) {
+            return new Uint8Array(chunk);
+        }
+        return ops.encode(String(chunk));
+    }
+
+    function concat(chunks) {
+        const result = new Uint8Array(chunks.reduce((size, chunk) => size + chunk.byteLength, 0));
+        let offset = 0;
+        for (const chunk of chunks) {
+            result.set(chunk, offset);
+            offset += chunk.byteLength;
+        }
+        return result;
+    }
+
+    class ReadableStreamDefaultReader {
+        constructor(stream) {
+            if (stream.locked) {
+                throw new TypeError("ReadableStream is locked");
+            }
+            stream._reader = this;
+            this._stream = stream;
+        }
+
+        read() {
+            if (!this._stream) {
+                return Promise.reject(new TypeError("Reader is released"));
+            }
+            return this._stream._read();
+        }
+
+        cancel(reason) {
+            return this._stream ? this._stream.cancel(reason) : Promise.resolve();
+        }
+
+        releaseLock() {
+            if (this._stream) {
+                this._stream._reader = null;
+                this._stream = null;
+            }
+        }
+    }
+
+    class ReadableStream {
+        constructor(source = {}) {
+            this._source = source;
+            this._queue = [];
+            this._closed = false;
+            this._errored = false;
+            this._error = undefined;
+            this._reader = null;
+            this._wake = null;
+            this._controller = {
+                enqueue: (chunk) => {
+                    if (!this._closed) {
+                        this._queue.push(chunk);
+                        this._notify();
+                    }
+                },
+                close: () => {
+                    this._closed = true;
+                    this._notify();
+                },
+                error: (e) => {
+                    this._errored = true;
+                    this._error = e;
+                    this._queue = [];
+                    this._notify();
+                },
+            };
+            this._started = Promise.resolve(source.start && source.start(this._controller));
+        }
+
+        get locked() {
+            return this._reader !== null;
+        }
+
+        getReader() {
+            return new ReadableStreamDefaultReader(this);
+        }
+
+        cancel(reason) {
+            this._closed = true;
+            this._queue = [];
+            return Promise.resolve(this._source.cancel && this._source.cancel(reason));
+        }
+
+        async *[Symbol.asyncIterator]() {
+            const reader = this.getReader();
+            try {
+                for (;;) {
+                    const { done, value } = await reader.read();
+                    if (done) {
+                        return;
+                    }
+                    yield value;
+                }
+            } finally {
+                reader.releaseLock();
+            }
+        }
+
+        _notify() {
+            if (this._wake) {
+                const wake = this._wake;
+                this._wake = null;
+                wake();
+            }
+        }
+
+        async _read() {
+            await this._started;
+            for (;;) {
+                if (this._errored) {
+                    throw this._error;
+                }
+                if (this._queue.length > 0) {
+                    return { done: false, value: this._queue.shift() };
+                }
+                if (this._closed) {
+                    return { done: true, value: undefined };
+                }
+                if (this._source.pull) {
+                    await this._source.pull(this._controller);
+                } else {
+                    await new Promise((resolve) => (this._wake = resolve));
+                }
+            }
+        }
+    }
+
+    class Headers {
+        constructor(init) {
+            Object.defineProperty(this, "_map", { value: new Map() });
+            if (init instanceof Headers) {
+                init.forEach((value, name) => this.append(name, value));
+            } else if (Array.isArray(init)) {
+                for (const [name, value] of init) {
+                    this.append(name, value);
+                }
+            } else if (init) {
+                for (const name of Object.keys(init)) {
+                    this.append(name, init[name]);
+                }
+            }
+        }
+
+        append(name, value) {
+            name = String(name).toLowerCase();
+            value = String(value).trim();
+            const values = this._map.get(name);
+            if (values) {
+                values.push(value);
+            } else {
+                this._map.set(name, [value]);
+            }
+        }
+
+        delete(name) {
+            this._map.delete(String(name).toLowerCase());
+        }
+
+        get(name) {
+            const values = this._map.get(String(name).toLowerCase());
+            return values ? values.join(", ") : null;
+        }
+
+        has(name) {
+            return this._map.has(String(name).toLowerCase());
+        }
+
+        set(name, value) {
+            this._map.set(String(name).toLowerCase(), [String(value).trim()]);
+        }
+
+        forEach(callback, thisArg) {
+            for (const [name, value] of this) {
+                callback.call(thisArg, value, name, this);
+            }
+        }
+
+        *entries() {
+            for (const name of [...this._map.keys()].sort()) {
+                yield [name, this.get(name)];
+            }
+        }
+
+        *keys() {
+            for (const [name] of this) {
+                yield name;
+            }
+        }
+
+        *values() {
+            for (const [, value] of this) {
+                yield value;
+            }
+        }
+
+        [Symbol.iterator]() {
+            return this.entries();
+        }
+    }
+
+    class Body {
+        _initBody(body, headers) {
+            this._bodyUsed = false;
+            if (body === null || body === undefined) {
+                this._body = null;
+                return;
+            }
+            if (body instanceof ReadableStream) {
+                this._body = body;
+                return;
+            }
+            if (typeof body === "string" && !headers.has("content-type")) {
+                headers.set("content-type", "text/plain;charset=UTF-8");
+            }
+            const bytes = toBytes(body);
+            this._body = new ReadableStream({
+                start(controller) {
+                    controller.enqueue(bytes);
+                    controller.close();
+                },
+            });
+        }
+
+        get body() {
+            return this._body;
+        }
+
+        get bodyUsed() {
+            return this._bodyUsed;
+        }
+
+        async arrayBuffer() {
+            const bytes = await this._consume();
+            return bytes.buffer.slice(bytes.byteOffset, bytes.byteOffset + bytes.byteLength);
+        }
+
+        bytes() {
+            return this._consume();
+        }
+
+        async text() {
+            return ops.decode(await this._consume());
+        }
+
+        async json() {
+            return JSON.parse(await this.text());
+        }
+
+        async _consume() {
+            if (this._bodyUsed) {
+                throw new TypeError("Body has already been consumed");
+            }
+            this._bodyUsed = true;
+            if (this._body === null) {
+                return new Uint8Array(0);
+            }
+            const reader = this._body.getReader();
+            const chunks = [];
+            for (;;) {
+                const { done, value } = await reader.read();
+                if (done) {
+                    return concat(chunks);
+                }
+                chunks.push(toBytes(value));
+            }
+        }
+    }
+
+    class Request extends Body {
+        constructor(input, init = {}) {
+            super();
+            const source = input instanceof Request ? input : null;
+            this.url = source ? source.url : String(input);
+            this.method = String(init.method || (source ? source.method : "GET")).toUpperCase();
+            this.headers = new Headers(init.headers || (source ? source.headers : undefined));
+            this.signal = init.signal || (source ? source.signal : null);
+            let body = init.body;
+            if (body === undefined && source) {
+                body = source._body;
+            }
+            if (body !== null && body !== undefined && (this.method === "GET" || this.method === "HEAD")) {
+                throw new TypeError("Request with GET/HEAD method cannot have body");
+            }
+            this._initBody(body, this.headers);
+        }
+    }
+
+    class Response extends Body {
+        constructor(body = null, init = {}) {
+            super();
+            this.status = init.status === undefined ? 200 : init.status;
+            this.statusText = init.statusText || "";
+            this.headers = new Headers(init.headers);
+            this.url = "";
+            this.type = "default";
+            this.redirected = false;
+            this._initBody(body, this.headers);
+        }
+
+        get ok() {
+            return this.status >= 200 && this.status < 300;
+        }
+
+        static json(data, init = {}) {
+            const headers = new Headers(init.headers);
+            if (!headers.has("content-type")) {
+                headers.set("content-type", "application/json");
+            }
+            return new Response(JSON.stringify(data), { ...init, headers });
+        }
+
+        static redirect(url, status = 302) {
+            return new Response(null, { status, headers: { location: String(url) } });
+        }
+    }
+
+    class AbortSignal {
+        constructor() {
+            this.aborted = false;
+            this.reason = undefined;
+            this.onabort = null;
+            Object.defineProperty(this, "_listeners", { value: [] });
+        }
+
+        addEventListener(type, listener) {
+            if (type === "abort") {
+                this._listeners.push(listener);
+            }
+        }
+
+        removeEventListener(type, listener) {
+            const index = this._listeners.indexOf(listener);
+            if (type === "abort" && index >= 0) {
+                this._listeners.splice(index, 1);
+            }
+        }
+
+        throwIfAborted() {
+            if (this.aborted) {
+                throw this.reason;
+            }
+        }
+
+        static abort(reason) {
+            const controller = new AbortController();
+            controller.abort(reason);
+            return controller.signal;
+        }
+
+        static timeout(ms) {
+            const controller = new AbortController();
+            setTimeout(() => controller.abort(new DOMException("The operation timed out", "TimeoutError")), ms);
+            return controller.signal;
+        }
+
+        _abort(reason) {
+            if (this.aborted) {
+                return;
+            }
+            this.aborted = true;
+            this.reason = reason === undefined ? abortError() : reason;
+            const event = { type: "abort", target: this };
+            if (this.onabort) {
+                this.onabort(event);
+            }
+            for (const listener of this._listeners.splice(0)) {
+                listener(event);
+            }
+        }
+    }
+
+    class AbortController {
+        constructor() {
+            this.signal = new AbortSignal();
+        }
+
+        abort(reason) {
+            this.signal._abort(reason);
+        }
+    }
+
+    async function fetch(input, init = {}) {
+        const request = new Request(input, init);
+        const signal = request.signal;
+        if (signal) {
+            signal.throwIfAborted();
+        }
+        const body = request.body === null ? undefined : await request.bytes();
+        const [id, head] = ops.fetch(request.method, request.url, [...request.headers], body);
+        let aborted = null;
+        const onAbort = () => {
+            aborted = signal.reason;
+            ops.close(id);
+        };
+        if (signal) {
+            signal.addEventListener("abort", onAbort);
+        }
+        const check = (e) => {
+            throw aborted === null ? e : aborted;
+        };
+        let status, statusText, url, headers;
+        try {
+            [status, statusText, url, headers] = await head;
+        } catch (e) {
+            check(e);
+        }
+
+        const stream = new ReadableStream({
+            async pull(controller) {
+                let chunk;
+                try {
+                    chunk = await ops.read(id);
+                } catch (e) {
+                    check(e);
+                }
+                if (chunk === undefined) {
+                    if (signal) {
+                        signal.removeEventListener("abort", onAbort);
+                    }
+                    controller.close();
+                } else {
+                    controller.enqueue(chunk);
+                }
+            },
+            cancel() {
+                ops.close(id);
+            },
+        });
+        const response = new Response(request.method === "HEAD" ? null : stream, { status, statusText, headers });
+        response.url = url;
+        return response;
+    }
+
+    Object.assign(global, {
+        fetch,
+        Headers,
+        Request,
+        Response,
+        ReadableStream,
+        AbortController,
+        AbortSignal,
+        DOMException,
+    });
+})
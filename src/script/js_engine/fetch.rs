@@ -0,0 +1,360 @@
+use crate::http_client::{self, HttpBody, HttpClient, HttpRequest, HttpResponse};
+use crate::script::js_engine::bindings;
+use crate::script::js_engine::js_isolate::Isolate;
+use bytes::Bytes;
+use futures::future::{abortable, AbortHandle, Aborted, FutureExt, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+use rusty_v8 as v8;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+
+static PRELUDE: &str = include_str!("fetch.js");
+static PRELUDE_NAME: &str = "laputa:fetch";
+
+pub(crate) enum OpResult {
+    Response(HttpResponse),
+    Chunk(HttpBody, Option<Bytes>),
+    Failed(String),
+    Aborted,
+}
+
+/// Pending operations of `fetch`, completed by the event loop of the isolate. Each fetch is a
+/// resource with at most one pending operation: sending the request or reading a body chunk.
+pub(crate) struct FetchOps {
+    client: HttpClient,
+    next_id: u32,
+    pending: FuturesUnordered<LocalBoxFuture<'static, (u32, OpResult)>>,
+    resolvers: HashMap<u32, v8::Global<v8::PromiseResolver>>,
+    aborts: HashMap<u32, AbortHandle>,
+    bodies: HashMap<u32, HttpBody>,
+}
+
+impl FetchOps {
+    pub(crate) fn new(client: HttpClient) -> Self {
+        FetchOps {
+            client,
+            next_id: 0,
+            pending: FuturesUnordered::new(),
+            resolvers: HashMap::new(),
+            aborts: HashMap::new(),
+            bodies: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Waits for the next operation done.
+    pub(crate) async fn next(&mut self) -> Option<(u32, OpResult)> {
+        self.pending.next().await
+    }
+
+    fn push<F>(&mut self, id: u32, resolver: v8::Global<v8::PromiseResolver>, op: F)
+    where
+        F: Future<Output = OpResult> + Send + 'static,
+    {
+        let (task, abort) = abortable(op);
+        self.resolvers.insert(id, resolver);
+        self.aborts.insert(id, abort);
+        let op = async move {
+            match http_client::spawn(task).await {
+                Ok(Ok(result)) => (id, result),
+                Ok(Err(Aborted)) => (id, OpResult::Aborted),
+                Err(e) => (id, OpResult::Failed(e)),
+            }
+        };
+        self.pending.push(op.boxed_local());
+    }
+
+    /// Aborts the pending operation of a fetch and drops its body.
+    fn close(&mut self, id: u32) {
+        if let Some(abort) = self.aborts.remove(&id) {
+            abort.abort();
+        }
+        self.bodies.remove(&id);
+    }
+
+    /// Cancels all fetches, e.g. at the end of a failed request.
+    pub(crate) fn clear<'s>(&mut self, scope: &mut impl v8::ToLocal<'s>) {
+        for (_, abort) in self.aborts.drain() {
+            abort.abort();
+        }
+        for (_, mut resolver) in self.resolvers.drain() {
+            resolver.reset(scope);
+        }
+        self.bodies.clear();
+        self.pending = FuturesUnordered::new();
+    }
+
+    /// Settles the promise of a done operation.
+    pub(crate) fn complete<'s>(
+        &mut self,
+        scope: &mut impl v8::ToLocal<'s>,
+        context: v8::Local<v8::Context>,
+        id: u32,
+        result: OpResult,
+    ) {
+        self.aborts.remove(&id);
+        let mut resolver = match self.resolvers.remove(&id) {
+            Some(resolver) => resolver,
+            None => return,
+        };
+        let mut promise = resolver.get(scope).unwrap();
+        resolver.reset(scope);
+        match result {
+            OpResult::Response(response) => {
+                let head = response_head(scope, context, &response);
+                self.bodies.insert(id, response.body);
+                promise.resolve(context, head.into());
+            }
+            OpResult::Chunk(body, Some(chunk)) => {
+                self.bodies.insert(id, body);
+                let chunk = bindings::uint8_array(scope, &chunk);
+                promise.resolve(context, chunk.into());
+            }
+            OpResult::Chunk(_, None) => {
+                promise.resolve(context, v8::undefined(scope).into());
+            }
+            OpResult::Failed(message) => {
+                let message = v8::String::new(scope, &message).unwrap();
+                let exception = v8::Exception::type_error(scope, message);
+                promise.reject(context, exception);
+            }
+            OpResult::Aborted => {
+                let message = v8::String::new(scope, "The operation was aborted").unwrap();
+                let exception = v8::Exception::error(scope, message);
+                promise.reject(context, exception);
+            }
+        }
+    }
+}
+
+/// `[status, statusText, url, [[name, value], ...]]` of a response.
+fn response_head<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    response: &HttpResponse,
+) -> v8::Local<'s, v8::Array> {
+    let headers = v8::Array::new(scope, response.headers.len() as i32);
+    for (i, (name, value)) in response.headers.iter().enumerate() {
+        let pair = v8::Array::new(scope, 2);
+        let name = v8::String::new(scope, name).unwrap();
+        let value = v8::String::new(scope, value).unwrap();
+        pair.set_index(context, 0, name.into());
+        pair.set_index(context, 1, value.into());
+        headers.set_index(context, i as u32, pair.into());
+    }
+    let head = v8::Array::new(scope, 4);
+    let status = v8::Integer::new(scope, response.status as i32);
+    let status_text = v8::String::new(scope, &response.status_text).unwrap();
+    let url = v8::String::new(scope, &response.url).unwrap();
+    head.set_index(context, 0, status.into());
+    head.set_index(context, 1, status_text.into());
+    head.set_index(context, 2, url.into());
+    head.set_index(context, 3, headers.into());
+    head
+}
+
+fn string_arg<'s>(scope: &mut impl v8::ToLocal<'s>, value: v8::Local<'s, v8::Value>) -> String {
+    value
+        .to_string(scope)
+        .map(|s| s.to_rust_string_lossy(scope))
+        .unwrap_or_default()
+}
+
+fn header_pairs<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    value: v8::Local<'s, v8::Value>,
+) -> Vec<(String, String)> {
+    let array = match v8::Local::<v8::Array>::try_from(value) {
+        Ok(array) => array,
+        Err(_) => return vec![],
+    };
+    let mut pairs = Vec::with_capacity(array.length() as usize);
+    for i in 0..array.length() {
+        let pair = match array
+            .get_index(scope, context, i)
+            .and_then(|pair| v8::Local::<v8::Array>::try_from(pair).ok())
+        {
+            Some(pair) => pair,
+            None => continue,
+        };
+        if let (Some(name), Some(value)) = (
+            pair.get_index(scope, context, 0),
+            pair.get_index(scope, context, 1),
+        ) {
+            pairs.push((string_arg(scope, name), string_arg(scope, value)));
+        }
+    }
+    pairs
+}
+
+fn new_resolver<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+) -> (v8::Global<v8::PromiseResolver>, v8::Local<'s, v8::Promise>) {
+    let resolver = v8::PromiseResolver::new(scope, context).unwrap();
+    let promise = resolver.get_promise(scope);
+    let mut handle = v8::Global::<v8::PromiseResolver>::new();
+    handle.set(scope, resolver);
+    (handle, promise)
+}
+
+/// `ops.fetch(method, url, headers, body)` sends a request, returns `[id, promise of head]`.
+fn op_fetch(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let context = scope.get_current_context().unwrap();
+    let body = args.get(3);
+    let body = match v8::Local::<v8::ArrayBufferView>::try_from(body) {
+        Ok(view) => bindings::view_bytes(view),
+        Err(_) => Bytes::new(),
+    };
+    let request = HttpRequest {
+        method: string_arg(scope, args.get(0)),
+        url: string_arg(scope, args.get(1)),
+        headers: header_pairs(scope, context, args.get(2)),
+        body,
+    };
+
+    let isolate = unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+    let ops = &mut isolate.fetch;
+    let send = match ops.client.send(request) {
+        Ok(send) => send,
+        Err(message) => return bindings::throw_type_error(scope, &message),
+    };
+    ops.next_id = ops.next_id.wrapping_add(1).max(1);
+    let id = ops.next_id;
+    let (resolver, promise) = new_resolver(scope, context);
+    ops.push(id, resolver, async move {
+        match send.await {
+            Ok(response) => OpResult::Response(response),
+            Err(e) => OpResult::Failed(e),
+        }
+    });
+
+    let result = v8::Array::new(scope, 2);
+    let id = v8::Integer::new_from_unsigned(scope, id);
+    result.set_index(context, 0, id.into());
+    result.set_index(context, 1, promise.into());
+    rv.set(result.into());
+}
+
+/// `ops.read(id)` reads the next chunk of a response body, returns a promise of a `Uint8Array`
+/// or `undefined` at the end of the body.
+fn op_read(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let context = scope.get_current_context().unwrap();
+    let id = args.get(0).integer_value(scope).unwrap_or(0) as u32;
+
+    let isolate = unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+    let ops = &mut isolate.fetch;
+    if ops.resolvers.contains_key(&id) {
+        return bindings::throw_type_error(scope, "Body is being read");
+    }
+    let (mut resolver, promise) = new_resolver(scope, context);
+    match ops.bodies.remove(&id) {
+        Some(body) => ops.push(id, resolver, async move {
+            match body.chunk().await {
+                (body, Ok(chunk)) => OpResult::Chunk(body, chunk),
+                (_, Err(e)) => OpResult::Failed(e),
+            }
+        }),
+        // the body is done or closed
+        None => {
+            let mut local = resolver.get(scope).unwrap();
+            resolver.reset(scope);
+            local.resolve(context, v8::undefined(scope).into());
+        }
+    }
+    rv.set(promise.into());
+}
+
+/// `ops.close(id)` aborts a fetch and drops its response body.
+fn op_close(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let id = args.get(0).integer_value(scope).unwrap_or(0) as u32;
+    let isolate = unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+    isolate.fetch.close(id);
+}
+
+/// `ops.encode(string)` returns the UTF-8 bytes of a string as `Uint8Array`.
+fn op_encode(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let value = string_arg(scope, args.get(0));
+    rv.set(bindings::uint8_array(scope, value.as_bytes()).into());
+}
+
+/// `ops.decode(bytes)` decodes UTF-8 bytes to a string, invalid sequences are replaced.
+fn op_decode(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let mut hs = v8::HandleScope::new(scope);
+    let scope = hs.enter();
+    let bytes = match v8::Local::<v8::ArrayBufferView>::try_from(args.get(0)) {
+        Ok(view) => bindings::view_bytes(view),
+        Err(_) => Bytes::new(),
+    };
+    let value = String::from_utf8_lossy(&bytes);
+    rv.set(v8::String::new(scope, &value).unwrap().into());
+}
+
+/// Defines `fetch`, `Headers`, `Request`, `Response`, `ReadableStream` and `AbortController`
+/// in the global scope of a context.
+pub(crate) fn init<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    global: v8::Local<v8::Object>,
+) {
+    let ops = v8::Object::new(scope);
+    bindings::set_function(scope, context, ops, "fetch", op_fetch);
+    bindings::set_function(scope, context, ops, "read", op_read);
+    bindings::set_function(scope, context, ops, "close", op_close);
+    bindings::set_function(scope, context, ops, "encode", op_encode);
+    bindings::set_function(scope, context, ops, "decode", op_decode);
+
+    let source = v8::String::new(scope, PRELUDE).unwrap();
+    let name = v8::String::new(scope, PRELUDE_NAME).unwrap();
+    let origin = v8::ScriptOrigin::new(
+        name.into(),
+        v8::Integer::new(scope, 0),
+        v8::Integer::new(scope, 0),
+        v8::Boolean::new(scope, false),
+        v8::Integer::new(scope, 0),
+        v8::undefined(scope).into(),
+        v8::Boolean::new(scope, false),
+        v8::Boolean::new(scope, false),
+        v8::Boolean::new(scope, false),
+    );
+    let mut script = v8::Script::compile(scope, context, source, Some(&origin))
+        .expect("Cannot compile the fetch prelude");
+    let install = script.run(scope, context).unwrap();
+    let install = v8::Local::<v8::Function>::try_from(install).unwrap();
+    install
+        .call(scope, context, global.into(), &[ops.into(), global.into()])
+        .expect("Cannot install the fetch prelude");
+}
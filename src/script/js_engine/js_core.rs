@@ -1,31 +1,179 @@
-use crate::common::{make_channel, BoxErrResult, ScriptEvent, ScriptResultEvent, Sender};
+use crate::common::{
+    BoxErrResult, Receiver, RequestData, ResponseData, ResponseSender, ScriptEvent,
+};
+use crate::config::{EngineConfig, HttpClientConfig};
+use crate::http_client::HttpClient;
+use crate::script::engine_pool::{self, Engine};
 use crate::script::js_engine::js_isolate::Isolate;
-use futures::{SinkExt, StreamExt};
-use serde::export::Result::Err;
-use std::thread;
-
-pub fn start() -> BoxErrResult<Sender<ScriptEvent>> {
-    let (send, mut rev) = make_channel::<ScriptEvent>();
-    let thread_builder = thread::Builder::new().name("v8-vm".into());
-    thread_builder.spawn(move || {
-        log::info!("Starting v8(js) engine");
-        async_std::task::block_on(async {
-            let mut isolate = Isolate::new();
-            while let Some(event) = rev.next().await {
-                let mut sender = event.sender;
-                let location = event.location;
-                let req = event.request;
-                let result = isolate
-                    .module_execute(location, req)
-                    .await
-                    .map_err(|e| e.to_string());
-                let r_event = ScriptResultEvent { result };
-                if let Err(e) = sender.send(r_event).await {
-                    log::error!("Error in broker: {}", e);
-                }
-                sender.close_channel();
+use futures::StreamExt;
+use rusty_v8 as v8;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub fn start(config: &EngineConfig, http_client: &HttpClientConfig) -> BoxErrResult<Engine> {
+    let engine_config = config.clone();
+    let client = HttpClient::new(http_client)?;
+    engine_pool::start("v8-vm", config, move |rev| {
+        run_worker(rev, &engine_config, client.clone())
+    })
+}
+
+#[derive(Default)]
+struct WatchState {
+    done: bool,
+    timed_out: bool,
+}
+
+/// Terminates the running script of an isolate when the execution is not done in time.
+struct Watchdog {
+    state: Arc<Mutex<WatchState>>,
+}
+
+impl Watchdog {
+    fn start(handle: v8::IsolateHandle, timeout: Duration) -> Self {
+        Watchdog::with_terminate(timeout, move || {
+            handle.terminate_execution();
+        })
+    }
+
+    fn with_terminate<F>(timeout: Duration, terminate: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(WatchState::default()));
+        let task_state = state.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(timeout).await;
+            let mut state = task_state.lock().unwrap();
+            if !state.done {
+                state.timed_out = true;
+                terminate();
+            }
+        });
+        Watchdog { state }
+    }
+
+    /// Whether the watchdog fired, the execution may have ended right before it did.
+    fn finish(self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.done = true;
+        state.timed_out
+    }
+}
+
+/// Whether the script is cut by the watchdog: a script which ended with a response right at
+/// the deadline, before the watchdog fired, keeps its response.
+fn terminated(timed_out: bool, result: &BoxErrResult<ResponseData>) -> bool {
+    timed_out && result.is_err()
+}
+
+fn timeout_response() -> ResponseData {
+    ResponseData::error(504, "Script execution timed out".to_string())
+}
+
+async fn execute(
+    isolate: &mut Isolate,
+    location: String,
+    req: RequestData,
+    deadline: Option<Instant>,
+    mut writer: ResponseSender,
+) {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => {
+            let result = isolate
+                .module_execute(location, req, None, &mut writer)
+                .await;
+            return finish(isolate, writer, result);
+        }
+    };
+    let now = Instant::now();
+    if deadline <= now {
+        return writer.fail(timeout_response());
+    }
+
+    let watchdog = Watchdog::start(isolate.v8_isolate.thread_safe_handle(), deadline - now);
+    let result = isolate
+        .module_execute(location.clone(), req, Some(deadline), &mut writer)
+        .await;
+    let timed_out = watchdog.finish();
+    if timed_out {
+        // a termination requested after the script is done must not hit the next one
+        isolate.v8_isolate.cancel_terminate_execution();
+    }
+    if terminated(timed_out, &result) {
+        log::error!("[JS]  Script {} is terminated on timeout", location);
+        return writer.fail(timeout_response());
+    }
+    finish(isolate, writer, result)
+}
+
+fn finish(isolate: &Isolate, writer: ResponseSender, result: BoxErrResult<ResponseData>) {
+    if isolate.heap_exhausted {
+        writer.fail(memory_limit_response())
+    } else {
+        writer.finish(result.map_err(|e| e.to_string()))
+    }
+}
+
+fn memory_limit_response() -> ResponseData {
+    ResponseData::error(500, "Script exceeded the memory limit".to_string())
+}
+
+fn run_worker(mut rev: Receiver<ScriptEvent>, config: &EngineConfig, client: HttpClient) {
+    log::info!("Starting v8(js) engine");
+    async_std::task::block_on(async {
+        let mut isolate = Isolate::new(config, client.clone());
+        while let Some(event) = rev.next().await {
+            let writer = ResponseSender::new(event.sender);
+            let location = event.location;
+            let req = event.request;
+            execute(&mut isolate, location.clone(), req, event.deadline, writer).await;
+            if isolate.heap_exhausted {
+                // the heap of a terminated isolate may still be full, start over with a new one
+                log::error!(
+                    "[JS]  Script {} exceeded the heap limit, recycling the isolate",
+                    location
+                );
+                isolate = Isolate::new(config, client.clone());
             }
+        }
+    });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_watchdog_at_deadline() {
+        let fired = Arc::new(AtomicBool::new(false));
+        let terminate = fired.clone();
+        let watchdog = Watchdog::with_terminate(Duration::from_millis(10), move || {
+            terminate.store(true, Ordering::SeqCst)
+        });
+        // the handler is done right at the deadline, the watchdog fires before it is finished
+        let result: BoxErrResult<ResponseData> = Ok(ResponseData {
+            status: 200,
+            headers: std::collections::HashMap::new(),
+            body: bytes::Bytes::from("done"),
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(fired.load(Ordering::SeqCst));
+        let timed_out = watchdog.finish();
+        assert!(timed_out);
+        assert!(!terminated(timed_out, &result));
+        assert!(terminated(timed_out, &Err("terminated".into())));
+
+        // a watchdog finished in time does not fire
+        let fired = Arc::new(AtomicBool::new(false));
+        let terminate = fired.clone();
+        let watchdog = Watchdog::with_terminate(Duration::from_millis(50), move || {
+            terminate.store(true, Ordering::SeqCst)
         });
-    })?;
-    Ok(send)
+        assert!(!watchdog.finish());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!fired.load(Ordering::SeqCst));
+    }
 }
@@ -1,20 +1,28 @@
-use crate::common::{BoxErrResult, RequestData, ResponseData};
-use crate::script::js_engine::bindings;
+use crate::common::{BoxErrResult, RequestData, ResponseData, ResponseSender};
+use crate::config::EngineConfig;
+use crate::http_client::{self, HttpClient};
+use crate::script::file_watcher::FileWatcher;
+use crate::script::js_engine::bindings::{self, BodyStream};
+use crate::script::js_engine::event_loop::Timers;
+use crate::script::js_engine::fetch::{FetchOps, OpResult};
 use bytes::{Buf, Bytes};
 use lazy_static::*;
 use log::*;
 use rusty_v8 as v8;
 use std::borrow::BorrowMut;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt::Formatter;
 use std::os::raw::c_void;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref INIT_LOCK: Mutex<u32> = Mutex::new(0);
 }
 
 static ROOT_MOD: &str = "___root_module__";
+static HANDLER_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
 
 #[must_use]
 struct SetupGuard {}
@@ -29,6 +37,7 @@ fn setup() -> SetupGuard {
     let mut g = INIT_LOCK.lock().unwrap();
     *g += 1;
     if *g == 1 {
+        v8::V8::set_flags_from_string("--harmony-top-level-await");
         v8::V8::initialize_platform(v8::new_default_platform());
         v8::V8::initialize();
     }
@@ -91,18 +100,36 @@ pub(crate) struct Modules {
     name_map: HashMap<String, i32>,
 }
 
+enum HandlerResult {
+    /// Result of the handler, `reload` if the module has to be evaluated again for the next request
+    Value {
+        value: v8::Global<v8::Value>,
+        reload: bool,
+    },
+    Response(ResponseData),
+}
+
 pub(crate) struct Isolate {
     pub(crate) v8_isolate: v8::OwnedIsolate,
     pub(crate) global_context: v8::Global<v8::Context>,
     pub(crate) modules: Modules,
     pub(crate) pending_promise_exceptions: HashMap<i32, v8::Global<v8::Value>>,
+    pub(crate) heap_exhausted: bool,
+    pub(crate) timers: Timers,
+    pub(crate) fetch: FetchOps,
+    event_loop_timeout: Duration,
+    watcher: FileWatcher,
 }
 
 impl Isolate {
-    pub fn new() -> Box<Self> {
+    pub fn new(config: &EngineConfig, client: HttpClient) -> Box<Self> {
+        let heap_limit = config.memory_limit;
         let _setup_guard = setup();
         let mut params = v8::Isolate::create_params();
         params.set_array_buffer_allocator(v8::new_default_allocator());
+        if let Some(limit) = heap_limit {
+            params.set_heap_limits(0, limit);
+        }
         let mut v8_isolate = v8::Isolate::new(params);
         v8_isolate.set_capture_stack_trace_for_uncaught_exceptions(true, 10);
         v8_isolate.set_promise_reject_callback(promise_reject_callback);
@@ -121,15 +148,24 @@ impl Isolate {
             modules,
             global_context,
             pending_promise_exceptions,
+            heap_exhausted: false,
+            timers: Timers::default(),
+            fetch: FetchOps::new(client),
+            event_loop_timeout: config.event_loop_timeout,
+            watcher: FileWatcher::new(),
         };
         let mut boxed_isolate = Box::new(my_isolate);
         {
             let isolate_ptr = Box::into_raw(boxed_isolate);
             boxed_isolate = unsafe { Box::from_raw(isolate_ptr) };
-            unsafe {
-                let v8_isolate = &mut boxed_isolate.v8_isolate;
-                v8_isolate.set_data(0, isolate_ptr as *mut c_void)
-            };
+            let v8_isolate = &mut boxed_isolate.v8_isolate;
+            unsafe { v8_isolate.set_data(0, isolate_ptr as *mut c_void) };
+            if heap_limit.is_some() {
+                v8_isolate.add_near_heap_limit_callback(
+                    near_heap_limit_callback,
+                    isolate_ptr as *mut c_void,
+                );
+            }
         }
         boxed_isolate
     }
@@ -219,6 +255,9 @@ impl Isolate {
         } else {
             debug!("[JS]  Module {} will be loaded", specifier);
             let source = resolve_spec(specifier.clone()).await?;
+            if let Some(file) = spec_file_path(&specifier) {
+                self.watcher.watch(file, &specifier);
+            }
             self.load_module_from_bytes(source, specifier, is_main)
         }
     }
@@ -271,10 +310,14 @@ impl Isolate {
                 map.get_mut(&id)
             }
             .unwrap();
-            let specs = module.imports.clone();
+            let specs = module
+                .imports
+                .iter()
+                .map(|spec| resolve_import(name, spec))
+                .collect::<Vec<String>>();
             if specs.len() > 0 {
                 debug!("[JS]  Begin to handle imported modules for module {}", name);
-                self.load_module_vec(*specs, false).await;
+                self.load_module_vec(specs, false).await;
             }
         }
 
@@ -334,7 +377,9 @@ impl Isolate {
         Ok(())
     }
 
-    fn module_evaluate(&mut self, mod_id: i32) -> BoxErrResult<ResponseData> {
+    /// Evaluates the module if it is not yet. With top-level await the evaluation result is a
+    /// promise, it is returned to be awaited on the event loop.
+    fn module_evaluate(&mut self, mod_id: i32) -> BoxErrResult<Option<v8::Global<v8::Value>>> {
         let v8_isolate = &mut self.v8_isolate;
         let mut hs = v8::HandleScope::new(v8_isolate);
         let scope = hs.enter();
@@ -350,52 +395,407 @@ impl Isolate {
         };
         let name = module.name.clone();
         let mut real_module = module.handle.get(scope).expect("Empty module handle");
-        let mut status = real_module.get_status();
-        if status == v8::ModuleStatus::Instantiated {
-            let result = real_module.evaluate(scope, context);
-            // Update status after evaluating.
-            status = real_module.get_status();
-            if result.is_some() {
-                assert!(
-                    status == v8::ModuleStatus::Evaluated || status == v8::ModuleStatus::Errored
-                );
-            } else {
-                assert_eq!(status, v8::ModuleStatus::Errored);
+        let result = match real_module.get_status() {
+            v8::ModuleStatus::Instantiated => real_module.evaluate(scope, context),
+            v8::ModuleStatus::Evaluated | v8::ModuleStatus::Errored => None,
+            _ => {
+                let err = JsError {
+                    message: format!("Module {} is not instantiated", name),
+                    cause: None,
+                };
+                return Err(Box::new(err));
             }
-            match status {
-                v8::ModuleStatus::Evaluated => {
-                    let result = result.unwrap();
-                    bindings::make_response(scope, context, result)
-                }
-                v8::ModuleStatus::Errored => {
-                    let exception = real_module.get_exception();
-                    let message = v8::Exception::create_message(scope, exception);
-                    print_error(scope, &message);
-                    let err_str = message.get(scope).to_rust_string_lossy(scope);
-                    let err = JsError {
-                        message: err_str,
-                        cause: None,
-                    };
-                    Err(Box::new(err))
+        };
+        match real_module.get_status() {
+            v8::ModuleStatus::Evaluated => {}
+            v8::ModuleStatus::Errored => {
+                let exception = real_module.get_exception();
+                let message = v8::Exception::create_message(scope, exception);
+                print_error(scope, &message);
+                let err_str = message.get(scope).to_rust_string_lossy(scope);
+                let err = JsError {
+                    message: err_str,
+                    cause: None,
+                };
+                return Err(Box::new(err));
+            }
+            other => {
+                // evaluation was stopped without an exception, e.g. terminated on timeout
+                let err = JsError {
+                    message: format!(
+                        "Module {} evaluation was interrupted, status: {:?}",
+                        name, other
+                    ),
+                    cause: None,
+                };
+                return Err(Box::new(err));
+            }
+        }
+        match result {
+            Some(result) if result.is_promise() => {
+                let mut promise = v8::Global::<v8::Value>::new();
+                promise.set(scope, result);
+                Ok(Some(promise))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets the handler of the evaluated module for the request: the export named by the
+    /// request method, or the default export. A function handler is called with `request`, other
+    /// values are the response itself.
+    fn module_call(&mut self, mod_id: i32, method: &str) -> BoxErrResult<HandlerResult> {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        assert!(!self.global_context.is_empty());
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let module = self
+            .modules
+            .mod_map
+            .get(&mod_id)
+            .expect("ModuleInfo not found");
+        let name = module.name.clone();
+        let real_module = module.handle.get(scope).expect("Empty module handle");
+        let namespace = real_module.get_module_namespace().to_object(scope).unwrap();
+        let handler = match handler_export(scope, context, namespace, method) {
+            Some(handler) => handler,
+            None => {
+                let response = method_not_allowed(scope, context, namespace);
+                return Ok(HandlerResult::Response(response));
+            }
+        };
+        let mut value = v8::Global::<v8::Value>::new();
+        let handler = match v8::Local::<v8::Function>::try_from(handler) {
+            Ok(handler) => handler,
+            Err(_) => {
+                value.set(scope, handler);
+                return Ok(HandlerResult::Value {
+                    value,
+                    reload: true,
+                });
+            }
+        };
+
+        let request_key = v8::String::new(scope, "request").unwrap();
+        let request = context
+            .global(scope)
+            .get(scope, context, request_key.into())
+            .unwrap();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+        let result = handler.call(scope, context, namespace.into(), &[request]);
+        match result {
+            Some(result) => {
+                value.set(scope, result);
+                Ok(HandlerResult::Value {
+                    value,
+                    reload: false,
+                })
+            }
+            None => {
+                let message = match tc.message() {
+                    Some(message) => {
+                        print_error(scope, &message);
+                        message.get(scope).to_rust_string_lossy(scope)
+                    }
+                    None => format!("Handler of module {} was interrupted", name),
+                };
+                let err = JsError {
+                    message,
+                    cause: None,
+                };
+                Err(Box::new(err))
+            }
+        }
+    }
+
+    /// Builds the response from the settled result of a handler.
+    fn module_response(
+        &mut self,
+        mut value: v8::Global<v8::Value>,
+    ) -> BoxErrResult<(ResponseData, Option<BodyStream>)> {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let result = value.get(scope).unwrap();
+        value.reset(scope);
+        let result = bindings::settle(scope, result)?;
+        bindings::make_response(scope, context, result)
+    }
+
+    /// Evaluates the module and runs its handler, the event loop runs until the handler result
+    /// and the body stream are settled and no timer is pending, or until `deadline`. The returned
+    /// flag tells if the module has to be evaluated again for the next request.
+    /// Instantiates and evaluates a module which is not yet, with its top-level await.
+    async fn module_init(&mut self, mod_id: i32, deadline: Instant) -> BoxErrResult<()> {
+        self.instantiate_module(mod_id).await?;
+        if let Some(promise) = self.module_evaluate(mod_id)? {
+            let done = self.run_event_loop(Some(&promise), deadline).await;
+            let settled = self.settle_global(promise);
+            done?;
+            settled?;
+        }
+        Ok(())
+    }
+
+    /// Runs the handler of the method, `reload` is set when the module has no handler function
+    /// and its response is made at top level.
+    async fn module_run(
+        &mut self,
+        mod_id: i32,
+        method: &str,
+        deadline: Instant,
+        writer: &mut ResponseSender,
+        reload: &mut bool,
+    ) -> BoxErrResult<ResponseData> {
+        let value = match self.module_call(mod_id, method)? {
+            HandlerResult::Response(response) => return Ok(response),
+            HandlerResult::Value {
+                value,
+                reload: top_level,
+            } => {
+                *reload |= top_level;
+                value
+            }
+        };
+        if let Err(e) = self.run_event_loop(Some(&value), deadline).await {
+            self.drop_global(value);
+            return Err(e);
+        }
+        let (response, stream) = self.module_response(value)?;
+        if let Some(mut stream) = stream {
+            let head = ResponseData {
+                status: response.status,
+                headers: response.headers.clone(),
+                body: Bytes::new(),
+            };
+            writer.send_head(head)?;
+            let result = self.send_stream(&stream, deadline, writer).await;
+            self.reset_stream(&mut stream);
+            result?;
+            self.run_event_loop(None, deadline).await?;
+        }
+        Ok(response)
+    }
+
+    /// Sends the chunks of a streaming body as soon as they are read.
+    async fn send_stream(
+        &mut self,
+        stream: &BodyStream,
+        deadline: Instant,
+        writer: &mut ResponseSender,
+    ) -> BoxErrResult<()> {
+        loop {
+            let chunk = self.read_stream(stream)?;
+            if let Err(e) = self.run_event_loop(Some(&chunk), deadline).await {
+                self.drop_global(chunk);
+                return Err(e);
+            }
+            match self.take_chunk(chunk)? {
+                Some(chunk) => writer.send_chunk(chunk)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn read_stream(&mut self, stream: &BodyStream) -> BoxErrResult<v8::Global<v8::Value>> {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let result = stream.read(scope, context)?;
+        let mut chunk = v8::Global::<v8::Value>::new();
+        chunk.set(scope, result);
+        Ok(chunk)
+    }
+
+    fn take_chunk(&mut self, mut chunk: v8::Global<v8::Value>) -> BoxErrResult<Option<Bytes>> {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let result = chunk.get(scope).unwrap();
+        chunk.reset(scope);
+        bindings::stream_chunk(scope, context, result)
+    }
+
+    fn reset_stream(&mut self, stream: &mut BodyStream) {
+        let mut hs = v8::HandleScope::new(&mut self.v8_isolate);
+        let scope = hs.enter();
+        stream.reset(scope);
+    }
+
+    /// Checks that a promise is fulfilled, the handle is released.
+    fn settle_global(&mut self, mut value: v8::Global<v8::Value>) -> BoxErrResult<()> {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let result = value.get(scope).unwrap();
+        value.reset(scope);
+        bindings::settle(scope, result).map(|_| ())
+    }
+
+    fn drop_global<T>(&mut self, mut handle: v8::Global<T>) {
+        let mut hs = v8::HandleScope::new(&mut self.v8_isolate);
+        let scope = hs.enter();
+        handle.reset(scope);
+    }
+
+    /// Runs microtasks, timers and fetches until `promise` is settled and no task is pending.
+    /// Past the deadline the pending tasks are cancelled, and it is an error if the promise is
+    /// still pending.
+    async fn run_event_loop(
+        &mut self,
+        promise: Option<&v8::Global<v8::Value>>,
+        deadline: Instant,
+    ) -> BoxErrResult<()> {
+        loop {
+            self.run_timers();
+            let settled = promise.map(|p| self.is_settled(p)).unwrap_or(true);
+            if settled && self.timers.is_empty() && self.fetch.is_empty() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                self.clear_tasks();
+                if settled {
+                    warn!("[JS]  Pending tasks are cancelled at the end of the request");
+                    return Ok(());
                 }
-                other => {
-                    panic!("Unexpected module status {:?}", other);
+                let err = JsError {
+                    message: "Pending tasks are not finished in time".to_string(),
+                    cause: None,
+                };
+                return Err(Box::new(err));
+            }
+            let wake = self
+                .timers
+                .next_due()
+                .map_or(deadline, |due| due.min(deadline));
+            if !self.fetch.is_empty() {
+                let wait = wake.saturating_duration_since(now);
+                if let Ok(Some((id, result))) =
+                    async_std::future::timeout(wait, self.fetch.next()).await
+                {
+                    self.complete_fetch(id, result);
                 }
+            } else if self.timers.is_empty() {
+                let err = JsError {
+                    message: "Promise is pending without any task to settle it".to_string(),
+                    cause: None,
+                };
+                return Err(Box::new(err));
+            } else if wake > now {
+                async_std::task::sleep(wake - now).await;
             }
-        } else {
-            let err = JsError {
-                message: format!("Module {} is not instantiated", name),
-                cause: None,
+        }
+    }
+
+    fn complete_fetch(&mut self, id: u32, result: OpResult) {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        self.fetch.complete(scope, context, id, result);
+    }
+
+    fn is_settled(&mut self, promise: &v8::Global<v8::Value>) -> bool {
+        let mut hs = v8::HandleScope::new(&mut self.v8_isolate);
+        let scope = hs.enter();
+        let value = promise.get(scope).unwrap();
+        match v8::Local::<v8::Promise>::try_from(value) {
+            Ok(promise) => promise.state() != v8::PromiseState::Pending,
+            Err(_) => true,
+        }
+    }
+
+    /// Runs the microtasks, then the timers due now.
+    fn run_timers(&mut self) {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        scope.isolate().run_microtasks();
+
+        let now = Instant::now();
+        for id in self.timers.due(now) {
+            // the timer may be cleared by a callback run before
+            let (callback, args, once) = match self.timers.get_mut(id) {
+                Some(timer) => {
+                    let callback = timer.callback.get(scope).unwrap();
+                    let args = timer
+                        .args
+                        .iter()
+                        .map(|arg| arg.get(scope).unwrap())
+                        .collect::<Vec<v8::Local<v8::Value>>>();
+                    if let Some(interval) = timer.interval {
+                        timer.due = now + interval;
+                    }
+                    (callback, args, timer.interval.is_none())
+                }
+                None => continue,
             };
-            Err(Box::new(err))
+            if once {
+                if let Some(mut timer) = self.timers.remove(id) {
+                    timer.reset(scope);
+                }
+            }
+
+            let global = context.global(scope);
+            let mut try_catch = v8::TryCatch::new(scope);
+            let tc = try_catch.enter();
+            if callback
+                .call(scope, context, global.into(), &args)
+                .is_none()
+            {
+                if let Some(message) = tc.message() {
+                    print_error(scope, &message);
+                }
+            }
+            scope.isolate().run_microtasks();
         }
     }
 
+    /// Cancels the pending timers and fetches.
+    fn clear_tasks(&mut self) {
+        let mut hs = v8::HandleScope::new(&mut self.v8_isolate);
+        let scope = hs.enter();
+        for mut timer in self.timers.drain() {
+            timer.reset(scope);
+        }
+        self.fetch.clear(scope);
+    }
+
     pub async fn module_execute(
         &mut self,
         specifier: String,
         request: RequestData,
+        deadline: Option<Instant>,
+        writer: &mut ResponseSender,
     ) -> BoxErrResult<ResponseData> {
+        let method = request.method.clone();
         let v8_isolate = &mut self.v8_isolate;
         let mut hs = v8::HandleScope::new(v8_isolate);
         let scope = hs.enter();
@@ -406,20 +806,191 @@ impl Isolate {
 
         let _ = bindings::make_request(scope, context, request)?;
 
-        // TODO: removing cached module makes a huge impact on performance, this code block
-        // must be removed when file watcher is ready.
-        let cache_map = &mut self.modules;
-        if let Some(&id) = cache_map.name_map.get(&specifier) {
-            cache_map.name_map.remove(&specifier);
-            cache_map.mod_map.remove(&id);
+        self.reload_changed_modules();
+
+        // pending promises and timers may keep the request open until then
+        let loop_deadline = Instant::now() + self.event_loop_timeout;
+        let loop_deadline = deadline.map_or(loop_deadline, |d| d.min(loop_deadline));
+        let id = self.load_module(specifier.clone(), true).await?;
+        // a module failed to instantiate or evaluate is reloaded too
+        let init = self.module_init(id, loop_deadline).await;
+        let mut reload = init.is_err();
+        let result = match init {
+            Ok(()) => {
+                self.module_run(id, &method, loop_deadline, writer, &mut reload)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.clear_tasks();
+        }
+
+        // a promise rejected without handler fails the request even if a response is made
+        let result = match self.take_rejection() {
+            Some(message) if result.is_ok() => Err(Box::new(JsError {
+                message: format!("Uncaught (in promise) {}", message),
+                cause: None,
+            }) as Box<dyn std::error::Error>),
+            _ => result,
+        };
+
+        // A module without handler functions builds its response from `request` at top level,
+        // so it is evaluated again for the next request. Otherwise the module stays cached until
+        // it is changed, a handler throwing does not reset the state of the module.
+        if reload {
+            let mut entry = HashSet::new();
+            entry.insert(specifier);
+            self.evict_modules(entry);
+        }
+        result
+    }
+
+    /// Message of the first promise rejected without handler, the recorded rejections are cleared.
+    fn take_rejection(&mut self) -> Option<String> {
+        if self.pending_promise_exceptions.is_empty() {
+            return None;
+        }
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+
+        let mut message = None;
+        for (_, mut handle) in self.pending_promise_exceptions.drain() {
+            if message.is_none() {
+                if let Some(reason) = handle.get(scope) {
+                    message = Some(bindings::value_message(scope, reason));
+                }
+            }
+            handle.reset(scope);
+        }
+        message
+    }
+
+    fn reload_changed_modules(&mut self) {
+        let changed = self.watcher.changed();
+        if changed.is_empty() {
+            return;
+        }
+        let imports = self
+            .modules
+            .mod_map
+            .values()
+            .map(|m| (m.name.clone(), (*m.imports).clone()))
+            .collect::<HashMap<String, Vec<String>>>();
+        let evicted = collect_dependents(changed, &imports);
+        self.evict_modules(evicted);
+    }
+
+    fn evict_modules(&mut self, names: HashSet<String>) {
+        let v8_isolate = &mut self.v8_isolate;
+        let mut hs = v8::HandleScope::new(v8_isolate);
+        let scope = hs.enter();
+        let modules = &mut self.modules;
+        for name in names {
+            if let Some(id) = modules.name_map.remove(&name) {
+                if let Some(mut module) = modules.mod_map.remove(&id) {
+                    debug!("[JS]  Module {} is removed from cache", name);
+                    module.handle.reset(scope);
+                }
+            }
         }
+    }
+}
+
+/// Called by v8 when the heap is about to reach its limit: the running script is terminated and
+/// the isolate is marked to be dropped by the engine, the limit is raised a little so that the
+/// termination can be done instead of crashing the process on OOM.
+extern "C" fn near_heap_limit_callback(
+    data: *mut c_void,
+    current_heap_limit: usize,
+    _initial_heap_limit: usize,
+) -> usize {
+    let isolate = unsafe { &mut *(data as *mut Isolate) };
+    if !isolate.heap_exhausted {
+        warn!("[JS]  Heap limit {} bytes is reached", current_heap_limit);
+        isolate.heap_exhausted = true;
+        isolate
+            .v8_isolate
+            .thread_safe_handle()
+            .terminate_execution();
+    }
+    current_heap_limit + current_heap_limit / 2
+}
 
-        let root_mod = format!("import m from \"{}\"\nm", specifier);
-        let root_bytes = Bytes::from(root_mod);
-        let root_id = self.load_module_from_bytes(root_bytes, ROOT_MOD.to_string(), true)?;
-        let _ = self.instantiate_module(root_id).await?;
-        self.module_evaluate(root_id)
+/// Export handling `method`: the one named by the method (`GET` for `HEAD` if missing), or the
+/// default export.
+fn handler_export<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    namespace: v8::Local<'s, v8::Object>,
+    method: &str,
+) -> Option<v8::Local<'s, v8::Value>> {
+    let mut names = vec![method];
+    if method == "HEAD" {
+        names.push("GET");
+    }
+    names.push("default");
+    for name in names {
+        let key = v8::String::new(scope, name).unwrap();
+        if let Some(value) = namespace.get(scope, context, key.into()) {
+            if !value.is_undefined() {
+                return Some(value);
+            }
+        }
     }
+    None
+}
+
+fn method_not_allowed<'s>(
+    scope: &mut impl v8::ToLocal<'s>,
+    context: v8::Local<v8::Context>,
+    namespace: v8::Local<'s, v8::Object>,
+) -> ResponseData {
+    let allowed = HANDLER_METHODS
+        .iter()
+        .filter(|name| {
+            let key = v8::String::new(scope, name).unwrap();
+            namespace
+                .get(scope, context, key.into())
+                .map(|value| value.is_function())
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect::<Vec<&str>>();
+    let mut response = ResponseData::error(405, "Method Not Allowed".to_string());
+    response
+        .headers
+        .insert("Allow".to_string(), allowed.join(", "));
+    response
+}
+
+/// Modules to drop from cache when the `changed` ones are modified: the changed modules and
+/// every module importing them, directly or not. `imports` are the specifiers as written in
+/// each module.
+fn collect_dependents(
+    changed: HashSet<String>,
+    imports: &HashMap<String, Vec<String>>,
+) -> HashSet<String> {
+    let mut result = HashSet::new();
+    let mut pending: Vec<String> = changed.into_iter().collect();
+    while let Some(name) = pending.pop() {
+        if !result.insert(name.clone()) {
+            continue;
+        }
+        for (importer, specs) in imports {
+            let imported = specs
+                .iter()
+                .any(|spec| resolve_import(importer, spec) == name);
+            if imported && !result.contains(importer) {
+                pending.push(importer.clone());
+            }
+        }
+    }
+    result
 }
 
 fn module_resolve_callback<'s>(
@@ -435,6 +1006,10 @@ fn module_resolve_callback<'s>(
     let referrer_id = referrer.get_identity_hash();
 
     let my_isolate: &mut Isolate = unsafe { &mut *(scope.isolate().get_data(0) as *mut Isolate) };
+    let specifier_str = match my_isolate.modules.mod_map.get(&referrer_id) {
+        Some(module) => resolve_import(&module.name, &specifier_str),
+        None => specifier_str,
+    };
 
     let specifier_id =
         async_std::task::block_on(my_isolate.load_module(specifier_str.clone(), false));
@@ -457,18 +1032,70 @@ fn module_resolve_callback<'s>(
     module.handle.get(scope).map(|m| scope.escape(m))
 }
 
-async fn resolve_spec(specifier: String) -> BoxErrResult<Bytes> {
+/// Resolves an import specifier against the name of the importing module: `./` and `../`
+/// specifiers are relative to the directory of the importer, the others are kept as is.
+fn resolve_import(referrer: &str, specifier: &str) -> String {
+    if !specifier.starts_with("./") && !specifier.starts_with("../") {
+        return specifier.to_string();
+    }
+    if referrer.starts_with("http://") || referrer.starts_with("https://") {
+        return url::Url::parse(referrer)
+            .and_then(|base| base.join(specifier))
+            .map(|url| url.to_string())
+            .unwrap_or_else(|_| specifier.to_string());
+    }
+    let (scheme, path) = if referrer.starts_with("file://") {
+        ("file://", &referrer[7..])
+    } else {
+        ("", referrer)
+    };
+    let mut segments = path
+        .split('/')
+        .enumerate()
+        .filter(|(i, part)| *part != "." && (*i == 0 || !part.is_empty()))
+        .map(|(_, part)| part)
+        .collect::<Vec<&str>>();
+    // the file name of the importer
+    segments.pop();
+    for part in specifier.split('/') {
+        match part {
+            "." | "" => {}
+            ".." => match segments.last() {
+                // the root of an absolute path
+                Some(&"") if segments.len() == 1 => {}
+                Some(&last) if last != ".." => {
+                    segments.pop();
+                }
+                _ => segments.push(".."),
+            },
+            part => segments.push(part),
+        }
+    }
+    format!("{}{}", scheme, segments.join("/"))
+}
+
+fn spec_file_path(specifier: &str) -> Option<&str> {
     if specifier.starts_with("http://") || specifier.starts_with("https://") {
-        let data = reqwest::get(&specifier).await?.bytes().await?;
-        Ok(data)
+        None
+    } else if specifier.starts_with("file:///") {
+        Some(&specifier[7..])
     } else {
-        let file_real = if specifier.starts_with("file:///") {
-            &specifier[7..]
-        } else {
-            &specifier
-        };
-        let bytes = async_std::fs::read(file_real).await?;
-        Ok(Bytes::from(bytes))
+        Some(specifier)
+    }
+}
+
+async fn resolve_spec(specifier: String) -> BoxErrResult<Bytes> {
+    match spec_file_path(&specifier) {
+        Some(file_real) => {
+            let bytes = async_std::fs::read(file_real).await?;
+            Ok(Bytes::from(bytes))
+        }
+        None => {
+            let data =
+                http_client::spawn(async move { reqwest::get(&specifier).await?.bytes().await })
+                    .await??;
+            Ok(data)
+        }
     }
 }
 
@@ -595,4 +1222,63 @@ pub mod tests {
 
         assert_eq!(format!("{}", err), "Current, cause: Source".to_string())
     }
+
+    #[test]
+    fn test_collect_dependents() {
+        let mut imports = HashMap::new();
+        imports.insert("main.js".to_string(), vec!["a.js".to_string()]);
+        imports.insert("a.js".to_string(), vec!["b.js".to_string()]);
+        imports.insert("c.js".to_string(), vec![]);
+        let mut changed = HashSet::new();
+        changed.insert("b.js".to_string());
+
+        let result = collect_dependents(changed, &imports);
+        assert_eq!(result.len(), 3);
+        assert!(result.contains("main.js"));
+        assert!(!result.contains("c.js"));
+    }
+
+    #[test]
+    fn test_collect_relative_dependents() {
+        let mut imports = HashMap::new();
+        imports.insert(
+            "deploy/api/main.js".to_string(),
+            vec!["./util.js".to_string(), "../lib/log.js".to_string()],
+        );
+        imports.insert("deploy/api/util.js".to_string(), vec![]);
+        imports.insert("deploy/lib/log.js".to_string(), vec![]);
+        imports.insert("deploy/other.js".to_string(), vec!["./util.js".to_string()]);
+        let mut changed = HashSet::new();
+        changed.insert("deploy/api/util.js".to_string());
+
+        let result = collect_dependents(changed, &imports);
+        assert_eq!(result.len(), 2);
+        assert!(result.contains("deploy/api/main.js"));
+        // `./util.js` of another directory is another module
+        assert!(!result.contains("deploy/other.js"));
+    }
+
+    #[test]
+    fn test_resolve_import() {
+        assert_eq!(
+            resolve_import("deploy/main.js", "./util.js"),
+            "deploy/util.js"
+        );
+        assert_eq!(
+            resolve_import("deploy/api/main.js", "../lib/./log.js"),
+            "deploy/lib/log.js"
+        );
+        assert_eq!(resolve_import("./main.js", "./util.js"), "util.js");
+        assert_eq!(resolve_import("main.js", "../util.js"), "../util.js");
+        assert_eq!(resolve_import("/srv/main.js", "../../util.js"), "/util.js");
+        assert_eq!(
+            resolve_import("file:///srv/app/main.js", "./util.js"),
+            "file:///srv/app/util.js"
+        );
+        assert_eq!(
+            resolve_import("https://example.com/lib/main.js", "../util.js"),
+            "https://example.com/util.js"
+        );
+        assert_eq!(resolve_import("deploy/main.js", "lib.js"), "lib.js");
+    }
 }
@@ -1,4 +1,6 @@
 mod bindings;
+mod event_loop;
+mod fetch;
 mod js_core;
 mod js_isolate;
 
@@ -1,75 +1,491 @@
-use rlua::{Lua, ThreadStatus};
-use std::thread;
+use rlua::{Context, HookTriggers, Lua, RegistryKey, ToLuaMulti};
 
 use crate::common::{
-    make_channel, BoxErrResult, ResponseData, ScriptEvent, ScriptResultEvent, Sender,
+    BoxErrResult, Receiver, RequestData, ResponseData, ResponseSender, ScriptEvent, StrErrResult,
 };
-use bytes::{BufMut, Bytes, BytesMut};
-use futures::{SinkExt, StreamExt};
-use serde::export::Option::Some;
+use crate::config::{EngineConfig, HttpClientConfig};
+use crate::http_client::{HttpClient, HttpRequest};
+use crate::script::engine_pool::{self, BusyGuard, Engine};
+use crate::script::lua_http::{self, Fetched};
+use bytes::Bytes;
+use futures::future::{self, Either, FutureExt, LocalBoxFuture};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
-pub fn start() -> BoxErrResult<Sender<ScriptEvent>> {
-    let (send, mut rev) = make_channel::<ScriptEvent>();
-    let thread_builder = thread::Builder::new().name("lua-vm".into());
-    thread_builder.spawn(move || {
-        log::info!("Starting lua engine");
-        async_std::task::block_on(async {
-            let lua = Lua::new();
-            while let Some(event) = rev.next().await {
-                let mut sender = event.sender;
-                let location = event.location;
-                let source = async_std::fs::read(location).await.unwrap();
-                let eval: BoxErrResult<ResponseData> = lua.context(|ctx| {
-                    let resp = ctx.load(source.as_slice()).call::<_, rlua::Table>(())?;
-                    let run: rlua::Function = resp.get("run")?;
-                    let result: rlua::MultiValue = run.call(())?;
-                    let mut data = ResponseData {
-                        status: 200,
-                        headers: HashMap::new(),
-                        body: Bytes::new(),
-                    };
-                    for value in result {
-                        match value {
-                            rlua::Value::Integer(int_value) => data.status = int_value as u16,
-                            rlua::Value::Table(table) => {
-                                let map = &mut data.headers;
-                                for pair in table.pairs::<rlua::Value, rlua::Value>() {
-                                    if let (rlua::Value::String(key), rlua::Value::String(value)) =
-                                        pair?
-                                    {
-                                        map.insert(
-                                            key.to_str()?.to_string(),
-                                            value.to_str()?.to_string(),
-                                        );
-                                    }
-                                }
-                            }
-                            rlua::Value::Function(function) => {
-                                let mut body = BytesMut::new();
-                                while let rlua::Value::String(line) =
-                                    function.call::<_, rlua::Value>(())?
-                                {
-                                    let line_bytes = line.as_bytes();
-                                    body.put(line_bytes);
-                                }
-                                data.body = body.freeze()
-                            }
-                            _ => log::error!("[LUA] Unsupported return type"),
-                        }
+const HOOK_INSTRUCTIONS: u32 = 1000;
+
+struct CachedModule {
+    table: RegistryKey,
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+fn content_hash(source: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn io_error_response(location: &str, e: std::io::Error) -> ResponseData {
+    log::error!("[LUA] Cannot read script {}: {}", location, e);
+    if e.kind() == std::io::ErrorKind::NotFound {
+        ResponseData::error(404, format!("Script {} not found", location))
+    } else {
+        ResponseData::error(500, format!("Cannot read script {}", location))
+    }
+}
+
+/// Makes sure the module table of `location` in cache is up to date, the script is loaded
+/// again only when its modified time or size changed and its content is different.
+async fn load_module(
+    lua: &Lua,
+    cache: &mut HashMap<String, CachedModule>,
+    location: &str,
+) -> Result<(), ResponseData> {
+    let meta = async_std::fs::metadata(location)
+        .await
+        .map_err(|e| io_error_response(location, e))?;
+    let modified = meta.modified().ok();
+    if let Some(cached) = cache.get(location) {
+        if cached.modified.is_some() && cached.modified == modified && cached.len == meta.len() {
+            return Ok(());
+        }
+    }
+
+    let source = async_std::fs::read(location)
+        .await
+        .map_err(|e| io_error_response(location, e))?;
+    let hash = content_hash(&source);
+    if let Some(cached) = cache.get_mut(location) {
+        if cached.hash == hash {
+            cached.modified = modified;
+            cached.len = meta.len();
+            return Ok(());
+        }
+    }
+
+    log::debug!("[LUA] Loading script {}", location);
+    let table = lua
+        .context(|ctx| {
+            let table = ctx
+                .load(source.as_slice())
+                .set_name(location)?
+                .call::<_, rlua::Table>(())?;
+            ctx.create_registry_value(table)
+        })
+        .map_err(|e| {
+            log::error!("[LUA] Cannot load script {}: {}", location, e);
+            ResponseData::error(500, format!("[LUA] {}", e))
+        })?;
+    let module = CachedModule {
+        table,
+        modified,
+        len: meta.len(),
+        hash,
+    };
+    if let Some(old) = cache.insert(location.to_string(), module) {
+        let _ = lua.context(|ctx| ctx.remove_registry_value(old.table));
+    }
+    Ok(())
+}
+
+fn make_request<'lua>(
+    ctx: Context<'lua>,
+    request: &RequestData,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let table = ctx.create_table()?;
+    table.set("method", request.method.as_str())?;
+    table.set("uri", request.uri.as_str())?;
+    table.set("path", request.path.as_str())?;
+    table.set("query", request.query.as_str())?;
+    table.set("body", ctx.create_string(&request.body[..])?)?;
+
+    let headers = ctx.create_table()?;
+    for name in request.headers.keys() {
+        let values = request
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap_or(""))
+            .collect::<Vec<&str>>()
+            .join(", ");
+        headers.set(name.as_str(), values)?;
+    }
+    table.set("headers", headers)?;
+
+    // same as `request.header(name)` in javascript, returns all values of the header
+    let header_map = request.headers.clone();
+    let header = ctx.create_function(move |ctx, name: String| {
+        let values = header_map
+            .get_all(name.as_str())
+            .iter()
+            .map(|v| v.to_str().unwrap_or("").to_string())
+            .collect::<Vec<String>>();
+        ctx.create_sequence_from(values)
+    })?;
+    table.set("header", header)?;
+
+    let params = ctx.create_table()?;
+    for (k, v) in &request.params {
+        params.set(k.as_str(), v.as_str())?;
+    }
+    table.set("params", params)?;
+
+    table.set("remote_addr", request.remote_addr.as_deref())?;
+    table.set("http_version", request.version.as_str())?;
+    let search_params = ctx.create_table()?;
+    for (k, values) in request.search_params() {
+        search_params.set(k, ctx.create_sequence_from(values)?)?;
+    }
+    table.set("search_params", search_params)?;
+    let cookies = ctx.create_table()?;
+    for (k, v) in request.cookies() {
+        cookies.set(k, v)?;
+    }
+    table.set("cookies", cookies)?;
+    Ok(table)
+}
+
+/// Builds the response from the values returned by `run`. A body function (e.g.
+/// `coroutine.wrap`) is streamed, each chunk is sent once yielded.
+fn response_data(
+    values: rlua::MultiValue,
+    writer: &mut ResponseSender,
+) -> rlua::Result<ResponseData> {
+    let mut data = ResponseData {
+        status: 200,
+        headers: HashMap::new(),
+        body: Bytes::new(),
+    };
+    let mut body_fn = None;
+    for value in values {
+        match value {
+            rlua::Value::Integer(int_value) => data.status = int_value as u16,
+            rlua::Value::String(body) => data.body = Bytes::copy_from_slice(body.as_bytes()),
+            rlua::Value::Table(table) => {
+                let map = &mut data.headers;
+                for pair in table.pairs::<rlua::Value, rlua::Value>() {
+                    if let (rlua::Value::String(key), rlua::Value::String(value)) = pair? {
+                        map.insert(key.to_str()?.to_string(), value.to_str()?.to_string());
                     }
-                    Ok(data)
-                });
-                let r_event = ScriptResultEvent {
-                    result: eval.map_err(|e| format!("[LUA] {}", e)),
-                };
-                if let Err(e) = sender.send(r_event).await {
-                    log::error!("[LUA] Error in broker: {}", e);
                 }
+            }
+            rlua::Value::Function(function) => body_fn = Some(function),
+            _ => log::error!("[LUA] Unsupported return type"),
+        }
+    }
+
+    if let Some(function) = body_fn {
+        let head = ResponseData {
+            status: data.status,
+            headers: data.headers.clone(),
+            body: Bytes::new(),
+        };
+        writer.send_head(head).map_err(rlua::Error::RuntimeError)?;
+        while let rlua::Value::String(chunk) = function.call::<_, rlua::Value>(())? {
+            writer
+                .send_chunk(Bytes::copy_from_slice(chunk.as_bytes()))
+                .map_err(rlua::Error::RuntimeError)?;
+        }
+    }
+    Ok(data)
+}
+
+/// A request handled by a coroutine running `run`, suspended while its http requests are
+/// pending so that the vm goes on with other requests.
+struct Task {
+    thread: RegistryKey,
+    writer: ResponseSender,
+    location: String,
+    deadline: Option<Instant>,
+    _guard: Option<BusyGuard>,
+}
+
+impl Task {
+    fn timed_out(&self) -> bool {
+        self.deadline.map(|d| Instant::now() >= d).unwrap_or(false)
+    }
+}
 
-                sender.close_channel();
+/// Values a task coroutine is resumed with.
+enum Resume {
+    Request(RequestData),
+    Http(StrErrResult<Fetched>),
+}
+
+enum Step {
+    Done(ResponseData),
+    Http(HttpRequest),
+}
+
+/// Resumes the coroutine of a task until it returns the response or yields an http request.
+fn resume(ctx: Context, task: &mut Task, input: Resume) -> rlua::Result<Step> {
+    let thread: rlua::Thread = ctx.registry_value(&task.thread)?;
+    let args = match input {
+        Resume::Request(request) => make_request(ctx, &request)?.to_lua_multi(ctx)?,
+        Resume::Http(result) => lua_http::response_values(ctx, result)?,
+    };
+    let values: rlua::MultiValue = thread.resume(args)?;
+    match thread.status() {
+        rlua::ThreadStatus::Resumable => match lua_http::yielded_request(values)? {
+            Some(request) => Ok(Step::Http(request)),
+            None => Err(rlua::Error::RuntimeError(
+                "Handler yields outside of a coroutine".to_string(),
+            )),
+        },
+        _ => Ok(Step::Done(response_data(values, &mut task.writer)?)),
+    }
+}
+
+pub fn start(config: &EngineConfig, http_client: &HttpClientConfig) -> BoxErrResult<Engine> {
+    let memory_limit = config.memory_limit;
+    let client = HttpClient::new(http_client)?;
+    engine_pool::start("lua-vm", config, move |rev| {
+        run_worker(rev, memory_limit, client.clone())
+    })
+}
+
+fn timeout_response() -> ResponseData {
+    ResponseData::error(504, "Script execution timed out".to_string())
+}
+
+fn memory_limit_response() -> ResponseData {
+    ResponseData::error(500, "Script exceeded the memory limit".to_string())
+}
+
+fn is_memory_error(e: &rlua::Error) -> bool {
+    match e {
+        rlua::Error::MemoryError(_) => true,
+        rlua::Error::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+/// Interrupts the running script with an error once the deadline of the current event is passed,
+/// checked every `HOOK_INSTRUCTIONS` vm instructions.
+fn set_deadline_hook(lua: &Lua) -> Arc<Mutex<Option<Instant>>> {
+    let deadline = Arc::new(Mutex::new(None::<Instant>));
+    let hook_deadline = deadline.clone();
+    let triggers = HookTriggers {
+        every_nth_instruction: Some(HOOK_INSTRUCTIONS),
+        ..Default::default()
+    };
+    lua.set_hook(triggers, move |_ctx, _debug| {
+        match *hook_deadline.lock().unwrap() {
+            Some(d) if Instant::now() >= d => {
+                Err(rlua::Error::RuntimeError("execution timed out".to_string()))
+            }
+            _ => Ok(()),
+        }
+    });
+    deadline
+}
+
+fn new_vm(memory_limit: Option<usize>) -> (Lua, Arc<Mutex<Option<Instant>>>) {
+    let lua = Lua::new();
+    lua.set_memory_limit(memory_limit);
+    if let Err(e) = lua_http::install(&lua) {
+        log::error!("[LUA] Cannot install the http module: {}", e);
+    }
+    let deadline = set_deadline_hook(&lua);
+    (lua, deadline)
+}
+
+type HttpOp = LocalBoxFuture<'static, (u64, StrErrResult<Fetched>)>;
+
+struct Worker {
+    lua: Lua,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    memory_limit: Option<usize>,
+    client: HttpClient,
+    cache: HashMap<String, CachedModule>,
+    tasks: HashMap<u64, Task>,
+    ops: FuturesUnordered<HttpOp>,
+    next_id: u64,
+}
+
+impl Worker {
+    fn new(memory_limit: Option<usize>, client: HttpClient) -> Self {
+        let (lua, deadline) = new_vm(memory_limit);
+        Worker {
+            lua,
+            deadline,
+            memory_limit,
+            client,
+            cache: HashMap::new(),
+            tasks: HashMap::new(),
+            ops: FuturesUnordered::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Handles events, and resumes the tasks whose http requests are done in the meantime.
+    async fn run(mut self, mut rev: Receiver<ScriptEvent>) {
+        let mut closed = false;
+        loop {
+            if self.ops.is_empty() {
+                if closed {
+                    return;
+                }
+                match rev.next().await {
+                    Some(event) => self.on_event(event).await,
+                    None => closed = true,
+                }
+                continue;
+            }
+            if closed {
+                if let Some((id, result)) = self.ops.next().await {
+                    self.on_http(id, result);
+                }
+                continue;
             }
+            let next = match future::select(rev.next(), self.ops.next()).await {
+                Either::Left((event, _)) => Either::Left(event),
+                Either::Right((op, _)) => Either::Right(op),
+            };
+            match next {
+                Either::Left(Some(event)) => self.on_event(event).await,
+                Either::Left(None) => closed = true,
+                Either::Right(Some((id, result))) => self.on_http(id, result),
+                Either::Right(None) => {}
+            }
+        }
+    }
+
+    async fn on_event(&mut self, event: ScriptEvent) {
+        let mut writer = ResponseSender::new(event.sender);
+        let location = event.location;
+        if event.deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+            writer.fail(timeout_response());
+            return;
+        }
+
+        if let Err(data) = load_module(&self.lua, &mut self.cache, &location).await {
+            writer.finish(Ok(data));
+            return;
+        }
+        let module = &self.cache[&location];
+        let thread = self.lua.context(|ctx| {
+            let table: rlua::Table = ctx.registry_value(&module.table)?;
+            let run: rlua::Function = table.get("run")?;
+            let thread = ctx.create_thread(run)?;
+            lua_http::register(ctx, thread.clone())?;
+            ctx.create_registry_value(thread)
         });
-    })?;
-    Ok(send)
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                writer.finish(Err(format!("[LUA] {}", e)));
+                return;
+            }
+        };
+
+        self.next_id += 1;
+        let task = Task {
+            thread,
+            writer,
+            location,
+            deadline: event.deadline,
+            _guard: event.guard,
+        };
+        self.step(self.next_id, task, Resume::Request(event.request));
+    }
+
+    fn on_http(&mut self, id: u64, result: StrErrResult<Fetched>) {
+        let task = match self.tasks.remove(&id) {
+            Some(task) => task,
+            None => return,
+        };
+        if task.timed_out() {
+            log::error!("[LUA] Script {} is terminated on timeout", task.location);
+            let _ = self
+                .lua
+                .context(|ctx| ctx.remove_registry_value(task.thread));
+            task.writer.fail(timeout_response());
+            return;
+        }
+        self.step(id, task, Resume::Http(result));
+    }
+
+    /// Runs a task until it is done, or until it waits for an http request.
+    fn step(&mut self, id: u64, mut task: Task, mut input: Resume) {
+        let result = loop {
+            *self.deadline.lock().unwrap() = task.deadline;
+            let step = self.lua.context(|ctx| resume(ctx, &mut task, input));
+            *self.deadline.lock().unwrap() = None;
+            match step {
+                Ok(Step::Http(request)) => match lua_http::fetch(&self.client, request) {
+                    Ok(fetch) => {
+                        let deadline = task.deadline;
+                        let op = async move {
+                            let result = match deadline {
+                                Some(deadline) => {
+                                    let wait = deadline.saturating_duration_since(Instant::now());
+                                    async_std::future::timeout(wait, fetch)
+                                        .await
+                                        .unwrap_or_else(|_| Err("Request timed out".to_string()))
+                                }
+                                None => fetch.await,
+                            };
+                            (id, result)
+                        };
+                        self.ops.push(op.boxed_local());
+                        self.tasks.insert(id, task);
+                        return;
+                    }
+                    Err(e) => input = Resume::Http(Err(e)),
+                },
+                Ok(Step::Done(data)) => break Ok(data),
+                Err(e) => break Err(e),
+            }
+        };
+
+        let Task {
+            thread,
+            writer,
+            location,
+            deadline,
+            ..
+        } = task;
+        let _ = self.lua.context(|ctx| ctx.remove_registry_value(thread));
+        let timed_out = deadline.map(|d| Instant::now() >= d).unwrap_or(false);
+        match result {
+            Err(e) if is_memory_error(&e) => {
+                log::error!(
+                    "[LUA] Script {} exceeded the memory limit, recycling the vm",
+                    location
+                );
+                writer.fail(memory_limit_response());
+                self.recycle();
+            }
+            Err(_) if timed_out => {
+                log::error!("[LUA] Script {} is terminated on timeout", location);
+                writer.fail(timeout_response())
+            }
+            result => writer.finish(result.map_err(|e| format!("[LUA] {}", e))),
+        }
+    }
+
+    /// Replaces the vm by a new one, the tasks waiting on the old one fail.
+    fn recycle(&mut self) {
+        for (_, task) in self.tasks.drain() {
+            task.writer.fail(memory_limit_response());
+        }
+        self.ops = FuturesUnordered::new();
+        self.cache.clear();
+        let (lua, deadline) = new_vm(self.memory_limit);
+        self.lua = lua;
+        self.deadline = deadline;
+    }
+}
+
+fn run_worker(rev: Receiver<ScriptEvent>, memory_limit: Option<usize>, client: HttpClient) {
+    log::info!("Starting lua engine");
+    async_std::task::block_on(Worker::new(memory_limit, client).run(rev));
 }
@@ -0,0 +1,228 @@
+use crate::common::StrErrResult;
+use crate::http_client::{self, HttpClient, HttpRequest};
+use bytes::Bytes;
+use rlua::{Context, LightUserData, Lua, MultiValue, Value};
+use std::future::Future;
+use std::os::raw::c_void;
+
+static HANDLERS_KEY: &str = "laputa_http_handlers";
+
+/// Address of this static marks the values yielded by the `http` functions.
+static YIELD_MARKER: u8 = 0;
+
+/// The `http` module: each function yields the request to the worker running the handler
+/// coroutine, which resumes it with the response once it is received.
+static HTTP_MODULE: &str = r#"
+local marker, handlers = ...
+local http = {}
+
+function http.request(options)
+    if type(options) == "string" then
+        options = { url = options }
+    end
+    if not handlers[coroutine.running()] then
+        error("http functions can only be called by the handler, not in a nested coroutine", 2)
+    end
+    return coroutine.yield(marker, options)
+end
+
+function http.get(url, headers)
+    return http.request({ method = "GET", url = url, headers = headers })
+end
+
+function http.post(url, body, headers)
+    return http.request({ method = "POST", url = url, body = body, headers = headers })
+end
+
+return http
+"#;
+
+fn marker() -> LightUserData {
+    LightUserData(&YIELD_MARKER as *const u8 as *mut c_void)
+}
+
+/// Defines the `http` module of a vm, available as a global and with `require("http")`.
+pub(crate) fn install(lua: &Lua) -> rlua::Result<()> {
+    lua.context(|ctx| {
+        let handlers = ctx.create_table()?;
+        let meta = ctx.create_table()?;
+        meta.set("__mode", "k")?;
+        handlers.set_metatable(Some(meta));
+        ctx.set_named_registry_value(HANDLERS_KEY, handlers.clone())?;
+
+        let http: rlua::Table = ctx
+            .load(HTTP_MODULE)
+            .set_name("http")?
+            .call((marker(), handlers))?;
+        let globals = ctx.globals();
+        if let Ok(package) = globals.get::<_, rlua::Table>("package") {
+            let loaded: rlua::Table = package.get("loaded")?;
+            loaded.set("http", http.clone())?;
+        }
+        globals.set("http", http)
+    })
+}
+
+/// Allows the `http` functions to be called by a handler coroutine.
+pub(crate) fn register<'lua>(ctx: Context<'lua>, thread: rlua::Thread<'lua>) -> rlua::Result<()> {
+    let handlers: rlua::Table = ctx.named_registry_value(HANDLERS_KEY)?;
+    handlers.set(thread, true)
+}
+
+/// The request yielded by an `http` function, `None` if the values are yielded by other code.
+pub(crate) fn yielded_request(values: MultiValue) -> rlua::Result<Option<HttpRequest>> {
+    let mut values = values.into_iter();
+    match values.next() {
+        Some(Value::LightUserData(m)) if m == marker() => {}
+        _ => return Ok(None),
+    }
+    let options = match values.next() {
+        Some(Value::Table(options)) => options,
+        _ => {
+            return Err(rlua::Error::RuntimeError(
+                "Invalid http request".to_string(),
+            ))
+        }
+    };
+    let method = options
+        .get::<_, Option<String>>("method")?
+        .unwrap_or_else(|| "GET".to_string());
+    let url = options
+        .get::<_, Option<String>>("url")?
+        .ok_or_else(|| rlua::Error::RuntimeError("Http request without url".to_string()))?;
+    let mut headers = vec![];
+    if let Some(table) = options.get::<_, Option<rlua::Table>>("headers")? {
+        for pair in table.pairs::<String, String>() {
+            headers.push(pair?);
+        }
+    }
+    let body = match options.get::<_, Option<rlua::String>>("body")? {
+        Some(body) => Bytes::copy_from_slice(body.as_bytes()),
+        None => Bytes::new(),
+    };
+    Ok(Some(HttpRequest {
+        method,
+        url,
+        headers,
+        body,
+    }))
+}
+
+/// A response read in full.
+pub(crate) struct Fetched {
+    status: u16,
+    status_text: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+/// Checks the request and returns the future sending it and reading the whole response.
+pub(crate) fn fetch(
+    client: &HttpClient,
+    request: HttpRequest,
+) -> StrErrResult<impl Future<Output = StrErrResult<Fetched>>> {
+    let send = client.send(request)?;
+    Ok(async move {
+        http_client::spawn(async move {
+            let response = send.await?;
+            let body = response.body.bytes().await?;
+            Ok(Fetched {
+                status: response.status,
+                status_text: response.status_text,
+                url: response.url,
+                headers: response.headers,
+                body,
+            })
+        })
+        .await?
+    })
+}
+
+/// Values returned by an `http` function: the response table, or `nil` and the error message.
+pub(crate) fn response_values<'lua>(
+    ctx: Context<'lua>,
+    result: StrErrResult<Fetched>,
+) -> rlua::Result<MultiValue<'lua>> {
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            let message = ctx.create_string(&e)?;
+            return Ok(MultiValue::from_vec(vec![
+                Value::Nil,
+                Value::String(message),
+            ]));
+        }
+    };
+    let table = ctx.create_table()?;
+    table.set("status", response.status)?;
+    table.set("status_text", response.status_text)?;
+    table.set("url", response.url)?;
+    let headers = ctx.create_table()?;
+    for (name, value) in response.headers {
+        let value = match headers.get::<_, Option<String>>(name.as_str())? {
+            Some(prev) => format!("{}, {}", prev, value),
+            None => value,
+        };
+        headers.set(name, value)?;
+    }
+    table.set("headers", headers)?;
+    table.set("body", ctx.create_string(&response.body[..])?)?;
+    Ok(MultiValue::from_vec(vec![Value::Table(table)]))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_yield() {
+        let lua = Lua::new();
+        install(&lua).unwrap();
+        lua.context(|ctx| {
+            let run: rlua::Function = ctx
+                .load(
+                    r#"
+                    return function(url)
+                        local res = http.post(url, "ping", { ["x-test"] = "yes" })
+                        return res.status, res.headers["x-test"], res.body
+                    end
+                "#,
+                )
+                .eval()
+                .unwrap();
+            let thread = ctx.create_thread(run.clone()).unwrap();
+            register(ctx, thread.clone()).unwrap();
+            let values: MultiValue = thread.resume("http://localhost/").unwrap();
+            let request = yielded_request(values).unwrap().unwrap();
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.url, "http://localhost/");
+            assert_eq!(
+                request.headers,
+                vec![("x-test".to_string(), "yes".to_string())]
+            );
+            assert_eq!(&request.body[..], b"ping");
+
+            let fetched = Fetched {
+                status: 201,
+                status_text: "Created".to_string(),
+                url: "http://localhost/".to_string(),
+                headers: vec![
+                    ("x-test".to_string(), "a".to_string()),
+                    ("x-test".to_string(), "b".to_string()),
+                ],
+                body: Bytes::from("pong"),
+            };
+            let args = response_values(ctx, Ok(fetched)).unwrap();
+            let (status, header, body): (u16, String, String) = thread.resume(args).unwrap();
+            assert_eq!(
+                (status, header.as_str(), body.as_str()),
+                (201, "a, b", "pong")
+            );
+
+            // only handler coroutines may call the http functions
+            let nested = ctx.create_thread(run).unwrap();
+            assert!(nested.resume::<_, MultiValue>("http://localhost/").is_err());
+        });
+    }
+}
@@ -1,2 +1,5 @@
+pub(crate) mod engine_pool;
+pub(crate) mod file_watcher;
 pub(crate) mod lua_engine;
+pub(crate) mod lua_http;
 pub(crate) mod js_engine;
\ No newline at end of file
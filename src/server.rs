@@ -1,13 +1,26 @@
 use crate::common::{
-    BoxErrResult, RequestData, ScriptEvent, ScriptResultEvent, Sender, ServiceState,
+    BoxErrResult, Receiver, RequestData, ScriptEvent, ScriptResultEvent, Sender, ServiceState,
 };
+use crate::compression::Compress;
+use crate::config::{BoundAddr, Methods, RouteOptions, ServerConfig, TrustedProxies};
+use crate::core::net::{self, Connection};
+use crate::core::peer::{self, PeerService};
+use crate::core::tls;
+use crate::middleware::{self, CapBodies, Middleware, Scoped, VaryOrigin};
+use crate::script::engine_pool::Engine;
 use crate::service::ScriptType;
-use crate::{common, inner_pages};
-use bytes::{BufMut, BytesMut};
-use futures::{SinkExt, StreamExt};
+use crate::shutdown::{self, Graceful, ShutdownHandle};
+use crate::{common, inner_pages, static_files};
+use futures::stream::BoxStream;
+use futures::{future, SinkExt, StreamExt, TryStreamExt};
 use mimalloc::MiMalloc;
 use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::http::Method;
 use tide::{Endpoint, Request, Response};
 
 #[global_allocator]
@@ -15,59 +28,180 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 pub struct Server {
     pub(crate) app: tide::Server<ServiceState>,
+    config: ServerConfig,
     sender_map: HashMap<ScriptType, Sender<ScriptEvent>>,
+    engines: Vec<Engine>,
+}
+
+pub struct BoundServer {
+    app: tide::Server<ServiceState>,
+    addrs: Vec<BoundAddr>,
+    incoming: BoxStream<'static, std::io::Result<Connection>>,
+    engines: Vec<Engine>,
+    compression: Option<usize>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    handle_signals: bool,
+    trusted_proxies: Arc<TrustedProxies>,
+}
+
+impl BoundServer {
+    pub fn local_addrs(&self) -> &[BoundAddr] {
+        &self.addrs
+    }
+
+    /// Handle to shut the running server down, besides the termination signals.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves until the server is shut down, then waits up to the shutdown timeout for the
+    /// requests in flight and the script engines to finish.
+    pub async fn run(self) -> BoxErrResult<()> {
+        if self.handle_signals {
+            self.shutdown.listen_signals();
+        }
+        let http_service = Arc::new(Graceful::new(
+            Compress::new(
+                VaryOrigin::new(CapBodies::new(self.app.into_http_service())),
+                self.compression,
+            ),
+            self.shutdown.clone(),
+        ));
+        let in_flight = http_service.in_flight();
+        // each connection is served on its own to pass its peer address to the requests
+        let mut incoming = self.incoming;
+        while let Some(conn) = incoming.next().await {
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("Error in accepting connection: {}", e);
+                    continue;
+                }
+            };
+            let service = PeerService::new(
+                http_service.clone(),
+                conn.peer_addr(),
+                self.trusted_proxies.clone(),
+            );
+            let conns = futures::stream::once(future::ok::<_, io::Error>(conn));
+            let serve = http_service_hyper::Server::builder(conns)
+                .with_spawner(net::Spawner)
+                .serve(service);
+            common::spawn_and_log_error(async move {
+                serve.await?;
+                Ok(())
+            });
+        }
+
+        log::info!("Shutting down server");
+        self.shutdown.shutdown();
+        let deadline = Instant::now() + self.shutdown_timeout;
+        if !shutdown::drain(&in_flight, deadline).await {
+            log::warn!(
+                "Shutdown timed out with {} requests in flight",
+                in_flight.load(std::sync::atomic::Ordering::SeqCst)
+            );
+        }
+        for engine in &self.engines {
+            engine.close();
+        }
+        let mut joined = true;
+        for engine in self.engines {
+            joined &= engine.join(deadline).await;
+        }
+        if !joined {
+            log::warn!("Shutdown timed out with script engines still running");
+        }
+        log::info!("Server is shut down");
+        Ok(())
+    }
 }
 
 async fn script_handle(
     path: std::path::PathBuf,
     mut engine_tx: Sender<ScriptEvent>,
     request_data: RequestData,
+    timeout: Option<Duration>,
 ) -> Response {
-    let (result_tx, mut result_rx) = common::make_channel::<ScriptResultEvent>();
+    let (result_tx, result_rx) = common::make_channel::<ScriptResultEvent>();
     let path_clone = path.clone();
     common::spawn_and_log_error(async move {
         let event = ScriptEvent {
             sender: result_tx.clone(),
             location: path_clone.to_string_lossy().to_string(),
             request: request_data,
+            deadline: timeout.map(|t| Instant::now() + t),
+            guard: None,
         };
         engine_tx.send(event).await?;
         Ok(())
     });
-    let mut result = BytesMut::new();
-    let mut status = 200;
-    let mut headers: Option<HashMap<String, String>> = None;
-    while let Some(r_event) = result_rx.next().await {
-        if let Err(e) = r_event.result {
-            log::error!("Error: {:?}, script: {:?}", e, path);
-            return Response::new(500).body_string(e.to_string());
-        }
-        let res = r_event.result.unwrap();
-        let bytes = res.body;
-        for byte in bytes {
-            result.put_u8(byte);
-        }
-        status = res.status;
-        headers.replace(res.headers);
-    }
-    let result_str = String::from_utf8(result.to_vec()).unwrap_or(String::new());
-    let mut resp = Response::new(status).body_string(result_str);
-    if let Some(map) = headers {
-        if !map.is_empty() {
-            for (k, v) in map {
-                let boxed_k = Box::new(k);
-                let ptr = Box::into_raw(boxed_k);
-                let k_static = unsafe { &*ptr as &String };
-                resp = resp.set_header(k_static, v);
-                unsafe { ptr::drop_in_place(ptr) }
+    let result = script_response(&path, result_rx);
+    match timeout {
+        Some(t) => match async_std::future::timeout(t, result).await {
+            Ok(resp) => resp,
+            Err(_) => {
+                log::error!("Script {:?} timed out after {:?}", path, t);
+                Response::new(504).body_string("Script execution timed out".to_string())
             }
+        },
+        None => result.await,
+    }
+}
+
+/// Builds the response from the first result event, the body is streamed from the following
+/// ones as soon as the script sends them.
+async fn script_response(
+    path: &std::path::Path,
+    mut result_rx: Receiver<ScriptResultEvent>,
+) -> Response {
+    let (head, streaming) = match result_rx.next().await {
+        Some(ScriptResultEvent {
+            result: Ok(head),
+            streaming,
+        }) => (head, streaming),
+        Some(ScriptResultEvent { result: Err(e), .. }) => {
+            log::error!("Error: {:?}, script: {:?}", e, path);
+            return Response::new(500).body_string(e);
         }
+        None => {
+            log::error!("No response from script: {:?}", path);
+            return Response::new(500).body_string("Script returns no response".to_string());
+        }
+    };
+
+    // scripts may set their own content type in headers, otherwise guess a text or binary one,
+    // the chunks of a streamed body are not known yet
+    let mime = if !streaming && std::str::from_utf8(&head.body).is_ok() {
+        mime::TEXT_PLAIN_UTF_8
+    } else {
+        mime::APPLICATION_OCTET_STREAM
+    };
+    let path = path.to_path_buf();
+    let chunks = result_rx.map(move |event| {
+        event.result.map(|data| data.body).map_err(|e| {
+            log::error!("Error: {:?}, script: {:?}", e, path);
+            io::Error::new(io::ErrorKind::Other, e)
+        })
+    });
+    let body = futures::stream::once(future::ready(Ok(head.body)))
+        .chain(chunks)
+        .boxed()
+        .into_async_read();
+    let mut resp = Response::new(head.status).body(body).set_mime(mime);
+    for (k, v) in head.headers {
+        let boxed_k = Box::new(k);
+        let ptr = Box::into_raw(boxed_k);
+        let k_static = unsafe { &*ptr as &String };
+        resp = resp.set_header(k_static, v);
+        unsafe { ptr::drop_in_place(ptr) }
     }
     resp
 }
 
 impl Server {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(config: ServerConfig) -> Self {
         let state = common::ServiceState::new();
         let mut app = tide::with_state(state);
         app.at("/").get(move |_| async {
@@ -78,21 +212,72 @@ impl Server {
         });
         Self {
             sender_map: HashMap::new(),
+            engines: vec![],
+            config,
             app,
         }
     }
 
+    pub async fn bind(self) -> common::BoxErrResult<BoundServer> {
+        let acceptor = match &self.config.tls {
+            Some(tls) => Some(tls::acceptor(tls)?),
+            None => None,
+        };
+        let (addrs, incoming) = net::bind_all(&self.config.listen_addrs(), acceptor).await?;
+        let (shutdown, stopped) = ShutdownHandle::new();
+        Ok(BoundServer {
+            app: self.app,
+            addrs,
+            incoming: shutdown::until_stopped(incoming, stopped),
+            engines: self.engines,
+            compression: self.config.compression,
+            shutdown,
+            shutdown_timeout: self.config.shutdown_timeout,
+            handle_signals: self.config.handle_signals,
+            trusted_proxies: Arc::new(self.config.trusted_proxies.clone()),
+        })
+    }
+
     pub async fn start(self) -> common::BoxErrResult<()> {
-        self.app.listen("127.0.0.1:8080").await?;
+        self.bind().await?.run().await
+    }
 
-        log::info!("Shutting down server");
-        drop(self.sender_map);
-        Ok(())
+    /// Adds a middleware for all the routes, the first one added runs first.
+    pub fn with_middleware(&mut self, middleware: impl Middleware<ServiceState>) -> &mut Self {
+        self.app.middleware(middleware);
+        self
+    }
+
+    /// Adds a middleware for the routes at `route_prefix` and below only.
+    pub fn with_middleware_at(
+        &mut self,
+        route_prefix: &str,
+        middleware: impl Middleware<ServiceState>,
+    ) -> &mut Self {
+        log::info!("Middleware for /{}", route_prefix.trim_matches('/'));
+        self.app.middleware(Scoped::new(route_prefix, middleware));
+        self
+    }
+
+    fn add_route(&mut self, route: &str, methods: Methods, ep: impl Endpoint<ServiceState>) {
+        let ep = Arc::new(ep);
+        let mut at = self.app.at(route);
+        match methods {
+            Methods::Any => {
+                at.all(move |req| ep.call(req));
+            }
+            Methods::Set(methods) => {
+                for method in methods {
+                    let ep = ep.clone();
+                    at.method(method, move |req| ep.call(req));
+                }
+            }
+        }
     }
 
     pub fn route_fn(
         &mut self,
-        method: tide::http::Method,
+        methods: impl Into<Methods>,
         route: &str,
         ep: impl Endpoint<ServiceState>,
     ) -> BoxErrResult<()> {
@@ -101,13 +286,13 @@ impl Server {
             route = &route[1..]
         }
         log::info!("Route /{} for native function", route);
-        self.app.at(route).method(method, ep);
+        self.add_route(route, methods.into(), ep);
         Ok(())
     }
 
     pub fn route_static(
         &mut self,
-        method: tide::http::Method,
+        methods: impl Into<Methods>,
         mime: mime::Mime,
         route: &str,
         path: &str,
@@ -117,63 +302,293 @@ impl Server {
             route = &route[1..]
         }
         log::info!("Router /{} for static file {}", route, path);
-        let path = std::path::PathBuf::from(path);
-        self.app.at(route).method(method, move |_| {
+        let path = Arc::new(std::path::PathBuf::from(path));
+        self.add_route(route, methods.into(), move |req: Request<ServiceState>| {
             let path = path.clone();
             let mime = mime.clone();
-            async {
-                let content = async_std::fs::read_to_string(path).await;
-                if let Err(e) = content {
-                    log::error!("Error in load static file: {}", e);
-                    return Response::new(404);
-                }
-                let content = content.unwrap();
-                Response::new(200).body_string(content).set_mime(mime)
-            }
+            let method = req.method().clone();
+            let headers = req.headers().clone();
+            async move { static_files::serve_file(&method, &headers, &path, mime).await }
         });
         Ok(())
     }
 
+    /// Serves the files under `dir` at `route_prefix` and below, with the mime type guessed from
+    /// their extension.
+    pub fn serve_dir(&mut self, route_prefix: &str, dir: &str) -> BoxErrResult<()> {
+        let route = route_prefix.trim_matches('/');
+        let root = std::fs::canonicalize(dir)
+            .map_err(|e| format!("Cannot serve directory {}: {}", dir, e))?;
+        if !root.is_dir() {
+            return Err(format!("Cannot serve directory {}: not a directory", dir).into());
+        }
+        log::info!("Route /{} for static directory {}", route, dir);
+        let root = Arc::new(root);
+        let ep = move |req: Request<ServiceState>| {
+            let root = root.clone();
+            async move { static_files::serve_dir(req, &root).await }
+        };
+        let methods = Methods::set(vec![Method::GET, Method::HEAD]);
+        let wildcard = if route.is_empty() {
+            "*path".to_string()
+        } else {
+            format!("{}/*path", route)
+        };
+        self.add_route(route, methods.clone(), ep.clone());
+        self.add_route(&wildcard, methods, ep);
+        Ok(())
+    }
+
     pub fn route_script(
         &mut self,
         script_type: ScriptType,
-        method: tide::http::Method,
+        methods: impl Into<Methods>,
         route: &str,
         path: &str,
+    ) -> BoxErrResult<()> {
+        self.route_script_with(script_type, methods, route, path, RouteOptions::new())
+    }
+
+    pub fn route_script_with(
+        &mut self,
+        script_type: ScriptType,
+        methods: impl Into<Methods>,
+        route: &str,
+        path: &str,
+        options: RouteOptions,
     ) -> BoxErrResult<()> {
         let mut route = route;
         if route.starts_with('/') {
             route = &route[1..]
         }
-        let map = &mut self.sender_map;
-        let tx = map
-            .entry(script_type)
-            .or_insert(script_type.start_engine()?);
-        log::info!("Route /{} for {} code form {}", route, script_type, path);
+        let engine_tx = if options.dedicated {
+            log::info!(
+                "Start dedicated {} engine for route /{}",
+                script_type,
+                route
+            );
+            let engine = script_type.start_engine(&self.config)?;
+            let tx = engine.sender.clone();
+            self.engines.push(engine);
+            tx
+        } else {
+            if !self.sender_map.contains_key(&script_type) {
+                let engine = script_type.start_engine(&self.config)?;
+                self.sender_map.insert(script_type, engine.sender.clone());
+                self.engines.push(engine);
+            }
+            self.sender_map[&script_type].clone()
+        };
+        let methods = methods.into();
+        log::info!(
+            "Route /{} ({}) for {} code form {}",
+            route,
+            methods,
+            script_type,
+            path
+        );
         let path = std::path::PathBuf::from(path);
-        let engine_tx = tx.clone();
-        self.app
-            .at(route)
-            .method(method, move |mut req: Request<ServiceState>| {
-                let path = path.clone();
-                let headers = req.headers().clone();
-                let uri = req.uri().clone();
-                let query = uri.query().unwrap_or("").to_string();
-                let uri_str = uri.to_string();
-                let engine_tx = engine_tx.clone();
-                async move {
-                    let body_fut = req.body_bytes();
-                    let body = body_fut.await.unwrap_or(Vec::new());
-                    let req_data = RequestData {
-                        headers,
-                        uri: uri_str,
-                        query,
-                        body: bytes::Bytes::from(body.clone()),
-                    };
-
-                    script_handle(path, engine_tx.clone(), req_data).await
+        let timeout = options.timeout.or(self.config.script_timeout);
+        let param_names = route_params(route);
+        self.add_route(route, methods, move |mut req: Request<ServiceState>| {
+            let path = path.clone();
+            let headers = req.headers().clone();
+            let method = req.method().to_string();
+            let uri = req.uri().clone();
+            let query = uri.query().unwrap_or("").to_string();
+            let uri_path = uri.path().to_string();
+            let uri_str = uri.to_string();
+            let remote_addr = peer::request_client_addr(&req);
+            let version = http_version(req.version()).to_string();
+            let params = param_names
+                .iter()
+                .map(|(name, key)| {
+                    let value = req.param::<String>(key).unwrap_or_default();
+                    (name.clone(), value)
+                })
+                .collect();
+            let engine_tx = engine_tx.clone();
+            async move {
+                let body = match req.body_bytes().await {
+                    Ok(body) => body,
+                    Err(e) if middleware::body_too_large(&e) => return Response::new(413),
+                    Err(e) => {
+                        log::error!("Error in read request body: {}", e);
+                        return Response::new(400);
+                    }
+                };
+                let req_data = RequestData {
+                    headers,
+                    method,
+                    uri: uri_str,
+                    path: uri_path,
+                    query,
+                    params,
+                    remote_addr,
+                    version,
+                    body: bytes::Bytes::from(body.clone()),
+                };
+
+                script_handle(path, engine_tx.clone(), req_data, timeout).await
+            }
+        });
+        Ok(())
+    }
+
+    pub fn route_dir(&mut self, path: &str) -> BoxErrResult<()> {
+        let root = Path::new(path);
+        log::info!("Route directory {}", path);
+        let mut files = vec![];
+        walk_dir(root, &mut files)?;
+        for file in files {
+            let relative = file.strip_prefix(root)?;
+            let file_str = file.to_string_lossy();
+            let script_type = file
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(ScriptType::from_extension);
+            match script_type {
+                Some(_) if is_module_only(relative) => {
+                    log::debug!("Skip route for script module {}", file_str);
                 }
-            });
+                Some(script_type) => {
+                    let route = dir_route(relative, true);
+                    self.route_script(script_type, Methods::Any, &route, &file_str)?;
+                }
+                None => {
+                    let mime = mime_guess::from_path(&file).first_or_octet_stream();
+                    let route = dir_route(relative, false);
+                    self.route_static(Method::GET, mime.clone(), &route, &file_str)?;
+                    if is_index(relative) {
+                        let dir = dir_route(relative, true);
+                        self.route_static(Method::GET, mime, &dir, &file_str)?;
+                    }
+                }
+            }
+        }
         Ok(())
     }
 }
+
+/// Names of the path params in a route, paired with the key to look them up in the router.
+/// An unnamed wildcard is exposed as `*`.
+fn route_params(route: &str) -> Vec<(String, String)> {
+    route
+        .split('/')
+        .filter(|segment| segment.starts_with(':') || segment.starts_with('*'))
+        .map(|segment| {
+            let key = segment[1..].to_string();
+            if key.is_empty() {
+                ("*".to_string(), key)
+            } else {
+                (key.clone(), key)
+            }
+        })
+        .collect()
+}
+
+fn http_version(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2.0",
+        _ => "1.1",
+    }
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for path in entries {
+        let hidden = path
+            .file_name()
+            .map(|name| name.to_string_lossy().starts_with('.'))
+            .unwrap_or(true);
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            walk_dir(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Scripts named `_*` or under a `_*` directory are modules imported by the handlers, they are
+/// not routed.
+fn is_module_only(relative: &Path) -> bool {
+    relative
+        .components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with('_'))
+}
+
+fn is_index(relative: &Path) -> bool {
+    relative.file_stem().map(|s| s == "index").unwrap_or(false)
+}
+
+/// Maps a file path relative to the routed directory into a url path, `index.*` files map to
+/// their directory when the extension is stripped.
+fn dir_route(relative: &Path, strip_ext: bool) -> String {
+    let mut segments: Vec<String> = relative
+        .parent()
+        .map(|p| {
+            p.components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if !strip_ext {
+        if let Some(name) = relative.file_name() {
+            segments.push(name.to_string_lossy().to_string());
+        }
+    } else if !is_index(relative) {
+        if let Some(stem) = relative.file_stem() {
+            segments.push(stem.to_string_lossy().to_string());
+        }
+    }
+    segments.join("/")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_route() {
+        assert_eq!(dir_route(Path::new("ping.js"), true), "ping");
+        assert_eq!(dir_route(Path::new("api/user.lua"), true), "api/user");
+        assert_eq!(dir_route(Path::new("api/index.js"), true), "api");
+        assert_eq!(dir_route(Path::new("index.html"), true), "");
+        assert_eq!(dir_route(Path::new("css/site.css"), false), "css/site.css");
+    }
+
+    #[test]
+    fn test_is_module_only() {
+        assert!(is_module_only(Path::new("_util.js")));
+        assert!(is_module_only(Path::new("_lib/db.lua")));
+        assert!(is_module_only(Path::new("api/_helpers/format.js")));
+        assert!(!is_module_only(Path::new("api/user.js")));
+        assert!(!is_module_only(Path::new("index.js")));
+    }
+
+    #[test]
+    fn test_route_params() {
+        let params = route_params("users/:id/files/*path");
+        assert_eq!(
+            params,
+            vec![
+                ("id".to_string(), "id".to_string()),
+                ("path".to_string(), "path".to_string())
+            ]
+        );
+        assert_eq!(
+            route_params("static/*"),
+            vec![("*".to_string(), "".to_string())]
+        );
+        assert!(route_params("ping").is_empty());
+    }
+}
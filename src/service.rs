@@ -1,4 +1,6 @@
-use crate::common::{BoxErrResult, ScriptEvent, Sender};
+use crate::common::BoxErrResult;
+use crate::config::ServerConfig;
+use crate::script::engine_pool::Engine;
 use serde::export::Formatter;
 
 static TYPE_NAMES: [&'static str; 2] = ["lua", "javascript"];
@@ -11,10 +13,22 @@ pub enum ScriptType {
 }
 
 impl ScriptType {
-    pub(crate) fn start_engine(&self) -> BoxErrResult<Sender<ScriptEvent>> {
+    pub(crate) fn start_engine(&self, config: &ServerConfig) -> BoxErrResult<Engine> {
         match self {
-            ScriptType::Lua => crate::script::lua_engine::start(),
-            ScriptType::JavaScript => crate::script::js_engine::start(),
+            ScriptType::Lua => {
+                crate::script::lua_engine::start(&config.lua_engine, &config.http_client)
+            }
+            ScriptType::JavaScript => {
+                crate::script::js_engine::start(&config.js_engine, &config.http_client)
+            }
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<ScriptType> {
+        match ext {
+            "lua" => Some(ScriptType::Lua),
+            "js" | "mjs" => Some(ScriptType::JavaScript),
+            _ => None,
         }
     }
 }
@@ -32,6 +46,18 @@ impl Clone for ScriptType {
     }
 }
 
+impl std::str::FromStr for ScriptType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lua" => Ok(ScriptType::Lua),
+            "javascript" | "js" => Ok(ScriptType::JavaScript),
+            other => Err(format!("Unknown script type: {}", other)),
+        }
+    }
+}
+
 impl std::fmt::Display for ScriptType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.pad(TYPE_NAMES[*self as usize])
@@ -0,0 +1,251 @@
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, Either, TryFutureExt};
+use futures::io::{AsyncBufRead, AsyncRead};
+use futures::stream::BoxStream;
+use futures::task::{Context, Poll};
+use futures::StreamExt;
+use http::header::{HeaderValue, CONNECTION};
+use http::StatusCode;
+use http_service::{Body, HttpService, Request, Response};
+use lazy_static::*;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::{Duration, Instant};
+
+const DRAIN_POLL_MS: u64 = 50;
+
+lazy_static! {
+    /// Servers stopped by the termination signals, the handler can only be set once.
+    static ref SIGNAL_TARGETS: Mutex<Vec<Weak<State>>> = Mutex::new(vec![]);
+}
+static SIGNAL_HANDLER: Once = Once::new();
+
+struct State {
+    triggered: AtomicBool,
+    stop_accept: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+/// Starts the graceful shutdown of a running server: it stops accepting connections, waits for
+/// the requests in flight and stops the script engines, then `run` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<State>,
+}
+
+impl ShutdownHandle {
+    /// The handle, and the receiver completed when the server stops accepting connections.
+    pub(crate) fn new() -> (Self, oneshot::Receiver<()>) {
+        let (stop_accept, stopped) = oneshot::channel();
+        let handle = ShutdownHandle {
+            state: Arc::new(State {
+                triggered: AtomicBool::new(false),
+                stop_accept: Mutex::new(Some(stop_accept)),
+            }),
+        };
+        (handle, stopped)
+    }
+
+    pub fn shutdown(&self) {
+        if !self.state.triggered.swap(true, Ordering::SeqCst) {
+            log::info!("Stop accepting connections");
+            if let Some(stop_accept) = self.state.stop_accept.lock().unwrap().take() {
+                let _ = stop_accept.send(());
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.state.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Shuts the server down on SIGINT or SIGTERM (Ctrl-C on windows), a second signal exits
+    /// the process at once.
+    pub(crate) fn listen_signals(&self) {
+        SIGNAL_HANDLER.call_once(|| {
+            let result = ctrlc::set_handler(|| {
+                let targets = SIGNAL_TARGETS.lock().unwrap();
+                let states = targets.iter().filter_map(Weak::upgrade);
+                let mut pending = false;
+                for state in states {
+                    let handle = ShutdownHandle { state };
+                    pending |= !handle.is_shutdown();
+                    handle.shutdown();
+                }
+                if !pending {
+                    log::warn!("Received termination signal again, exiting");
+                    std::process::exit(1);
+                }
+                log::info!("Received termination signal, shutting down");
+            });
+            if let Err(e) = result {
+                log::warn!("Cannot handle termination signals: {}", e);
+            }
+        });
+        let mut targets = SIGNAL_TARGETS.lock().unwrap();
+        targets.retain(|state| state.upgrade().is_some());
+        targets.push(Arc::downgrade(&self.state));
+    }
+}
+
+/// Ends the stream of incoming connections when `stopped` completes.
+pub(crate) fn until_stopped<T: Send + 'static>(
+    incoming: BoxStream<'static, T>,
+    stopped: oneshot::Receiver<()>,
+) -> BoxStream<'static, T> {
+    futures::stream::unfold((incoming, stopped), |(mut incoming, stopped)| async move {
+        match future::select(incoming.next(), stopped).await {
+            Either::Left((Some(conn), stopped)) => Some((conn, (incoming, stopped))),
+            _ => None,
+        }
+    })
+    .boxed()
+}
+
+/// Counts a request as in flight until its response body is sent or dropped.
+struct RequestGuard(Arc<AtomicUsize>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct GuardedBody {
+    body: Body,
+    _guard: RequestGuard,
+}
+
+impl AsyncRead for GuardedBody {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().body).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for GuardedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().body).consume(amt)
+    }
+}
+
+/// Tracks the requests in flight of the wrapped service. Once the server is shutting down,
+/// requests on kept alive connections are answered with 503 and the connection is closed.
+pub(crate) struct Graceful<S> {
+    inner: S,
+    handle: ShutdownHandle,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<S> Graceful<S> {
+    pub(crate) fn new(inner: S, handle: ShutdownHandle) -> Self {
+        Graceful {
+            inner,
+            handle,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Counter of the requests in flight, it lives on after the service is dropped.
+    pub(crate) fn in_flight(&self) -> Arc<AtomicUsize> {
+        self.in_flight.clone()
+    }
+}
+
+impl<S> HttpService for Graceful<S>
+where
+    S: HttpService,
+    <S::ResponseFuture as futures::TryFuture>::Error: Send,
+{
+    type Connection = S::Connection;
+    type ConnectionFuture = S::ConnectionFuture;
+    type ResponseFuture =
+        BoxFuture<'static, Result<Response, <S::ResponseFuture as futures::TryFuture>::Error>>;
+
+    fn connect(&self) -> Self::ConnectionFuture {
+        self.inner.connect()
+    }
+
+    fn respond(&self, conn: &mut Self::Connection, req: Request) -> Self::ResponseFuture {
+        if self.handle.is_shutdown() {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            resp.headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+            return Box::pin(futures::future::ok(resp));
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = RequestGuard(self.in_flight.clone());
+        let resp = self.inner.respond(conn, req).into_future();
+        Box::pin(async move {
+            let (parts, body) = resp.await?.into_parts();
+            let body = Body::from_reader(GuardedBody {
+                body,
+                _guard: guard,
+            });
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+/// Waits until no request is in flight, returns false if some are left at the deadline.
+pub(crate) async fn drain(in_flight: &AtomicUsize, deadline: Instant) -> bool {
+    loop {
+        if in_flight.load(Ordering::SeqCst) == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        async_std::task::sleep(Duration::from_millis(DRAIN_POLL_MS)).await;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        async_std::task::block_on(async {
+            assert!(drain(&in_flight, Instant::now()).await);
+
+            let guard = RequestGuard(in_flight.clone());
+            in_flight.fetch_add(1, Ordering::SeqCst);
+            let deadline = Instant::now() + Duration::from_millis(100);
+            assert!(!drain(&in_flight, deadline).await);
+
+            async_std::task::spawn(async move {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+                drop(guard);
+            });
+            let deadline = Instant::now() + Duration::from_secs(5);
+            assert!(drain(&in_flight, deadline).await);
+        });
+    }
+
+    #[test]
+    fn test_until_stopped() {
+        let (handle, stopped) = ShutdownHandle::new();
+        let (mut tx, rx) = futures::channel::mpsc::unbounded::<u32>();
+        let mut incoming = until_stopped(rx.boxed(), stopped);
+        async_std::task::block_on(async {
+            tx.start_send(1).unwrap();
+            assert_eq!(incoming.next().await, Some(1));
+            assert!(!handle.is_shutdown());
+            handle.clone().shutdown();
+            assert!(handle.is_shutdown());
+            tx.start_send(2).unwrap();
+            assert_eq!(incoming.next().await, None);
+        });
+    }
+}
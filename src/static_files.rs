@@ -0,0 +1,372 @@
+use crate::compression::{self, Encoding};
+use async_std::fs::{self, File};
+use async_std::io::{BufReader, SeekFrom};
+use async_std::prelude::*;
+use chrono::{DateTime, TimeZone, Utc};
+use percent_encoding::percent_decode_str;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tide::http::{HeaderMap, Method};
+use tide::{Request, Response};
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+const INDEX_FILE: &str = "index.html";
+static PRECOMPRESSED: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip];
+
+/// Byte range requested by the `Range` header.
+#[derive(Debug, PartialEq)]
+enum Range {
+    Full,
+    /// First and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Maps the url path `tail` to a path under `root`, `None` if it has hidden, parent or
+/// absolute segments.
+fn resolve_path(root: &Path, tail: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(tail).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+        if segment.starts_with('.') || segment.contains('\\') || segment.contains('\0') {
+            return None;
+        }
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(segment),
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Parses a single range of bytes, a set of several ranges is answered with the whole file.
+fn parse_range(header: &str, len: u64) -> Range {
+    let header = header.trim();
+    if !header.starts_with("bytes=") || header.contains(',') {
+        return Range::Full;
+    }
+    let spec = header["bytes=".len()..].trim();
+    let (start, end) = match spec.find('-') {
+        Some(i) => (&spec[..i], &spec[i + 1..]),
+        None => return Range::Full,
+    };
+    if start.is_empty() {
+        // suffix range, the last `n` bytes
+        return match end.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if len == 0 => Range::Unsatisfiable,
+            Ok(n) => Range::Partial(len.saturating_sub(n), len - 1),
+            Err(_) => Range::Full,
+        };
+    }
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Range::Full,
+    };
+    let end = if end.is_empty() {
+        None
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return Range::Full,
+        }
+    };
+    if start >= len {
+        return Range::Unsatisfiable;
+    }
+    let last = len - 1;
+    Range::Partial(start, end.map(|end| end.min(last)).unwrap_or(last))
+}
+
+/// Whether one of the tags of an `If-None-Match` header matches the etag, compared weakly.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    tags.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    if let Some(tags) = if_none_match {
+        return etag_matches(tags, etag);
+    }
+    match (if_modified_since.and_then(parse_http_date), last_modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Whether the validator of an `If-Range` header still matches the file, the etag is compared
+/// strongly.
+fn if_range_matches(
+    if_range: Option<&str>,
+    etag: &str,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    match if_range.map(|v| v.trim()) {
+        None => true,
+        Some(tag) if tag.starts_with('"') || tag.starts_with("W/") => tag == etag,
+        Some(date) => parse_http_date(date).map_or(false, |date| Some(date) == last_modified),
+    }
+}
+
+fn response(status: u16, headers: Vec<(&'static str, String)>) -> Response {
+    headers
+        .into_iter()
+        .fold(Response::new(status), |resp, (name, value)| {
+            resp.set_header(name, value)
+        })
+}
+
+/// Extension of the precompressed siblings of static files, deflate is only done on the fly.
+fn sibling_extension(encoding: Encoding) -> Option<&'static str> {
+    match encoding {
+        Encoding::Brotli => Some("br"),
+        Encoding::Gzip => Some("gz"),
+        Encoding::Deflate => None,
+    }
+}
+
+/// Opens the precompressed sibling of `path` (`.br` or `.gz`) with the best encoding accepted
+/// by the client, if one exists.
+async fn open_precompressed(path: &Path, accept: &str) -> Option<(File, fs::Metadata, Encoding)> {
+    let mut candidates = PRECOMPRESSED.to_vec();
+    while let Some(encoding) = compression::negotiate(accept, &candidates) {
+        candidates.retain(|e| *e != encoding);
+        let ext = match sibling_extension(encoding) {
+            Some(ext) => ext,
+            None => continue,
+        };
+        let mut name = path.as_os_str().to_owned();
+        name.push(".");
+        name.push(ext);
+        if let Ok(file) = File::open(PathBuf::from(name)).await {
+            match file.metadata().await {
+                Ok(meta) if meta.is_file() => return Some((file, meta, encoding)),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// Streams a file from disk, answering conditional and range requests. Only the headers are
+/// sent for `HEAD` requests.
+pub(crate) async fn serve_file(
+    method: &Method,
+    headers: &HeaderMap,
+    path: &Path,
+    mime: mime::Mime,
+) -> Response {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Error in load static file {:?}: {}", path, e);
+            return Response::new(404);
+        }
+    };
+    let mut meta = match file.metadata().await {
+        Ok(meta) if meta.is_file() => meta,
+        Ok(_) => return Response::new(404),
+        Err(e) => {
+            log::error!("Error in load static file {:?}: {}", path, e);
+            return Response::new(404);
+        }
+    };
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let mut encoding = None;
+    if let Some(accept) = header("accept-encoding") {
+        if let Some((sibling, sibling_meta, sibling_encoding)) =
+            open_precompressed(path, accept).await
+        {
+            file = sibling;
+            meta = sibling_meta;
+            encoding = Some(sibling_encoding);
+        }
+    }
+    let len = meta.len();
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+    let etag = format!(
+        "\"{:x}-{:x}{}\"",
+        len,
+        modified.map(|d| d.as_nanos()).unwrap_or(0),
+        encoding
+            .map(|e| format!("-{}", e.name()))
+            .unwrap_or_default()
+    );
+    let last_modified = modified.map(|d| Utc.timestamp(d.as_secs() as i64, 0));
+
+    let mut resp_headers = vec![
+        ("etag", etag.clone()),
+        ("accept-ranges", "bytes".to_string()),
+    ];
+    if let Some(encoding) = encoding {
+        resp_headers.push(("content-encoding", encoding.name().to_string()));
+        resp_headers.push(("vary", "accept-encoding".to_string()));
+    }
+    if let Some(date) = last_modified {
+        resp_headers.push(("last-modified", date.format(HTTP_DATE).to_string()));
+    }
+    if not_modified(
+        header("if-none-match"),
+        header("if-modified-since"),
+        &etag,
+        last_modified,
+    ) {
+        return response(304, resp_headers);
+    }
+
+    let range = match header("range") {
+        Some(range) if if_range_matches(header("if-range"), &etag, last_modified) => {
+            parse_range(range, len)
+        }
+        _ => Range::Full,
+    };
+    let (status, start, count) = match range {
+        Range::Full => (200, 0, len),
+        Range::Partial(start, end) => {
+            let range = format!("bytes {}-{}/{}", start, end, len);
+            resp_headers.push(("content-range", range));
+            (206, start, end - start + 1)
+        }
+        Range::Unsatisfiable => {
+            resp_headers.push(("content-range", format!("bytes */{}", len)));
+            return response(416, resp_headers);
+        }
+    };
+    resp_headers.push(("content-length", count.to_string()));
+    if *method == Method::HEAD {
+        return response(status, resp_headers).set_mime(mime);
+    }
+    if start > 0 {
+        if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+            log::error!("Error in read static file {:?}: {}", path, e);
+            return Response::new(500);
+        }
+    }
+    response(status, resp_headers)
+        .body(BufReader::new(file.take(count)))
+        .set_mime(mime)
+}
+
+/// Serves the file of the `path` param under the canonical `root`, a directory serves its
+/// `index.html`. Paths leaving the root, also through symlinks, are not found.
+pub(crate) async fn serve_dir<State>(req: Request<State>, root: &Path) -> Response {
+    let tail = req.param::<String>("path").unwrap_or_default();
+    let method = req.method().clone();
+    let headers = req.headers().clone();
+    let mut path = match resolve_path(root, &tail) {
+        Some(path) => path,
+        None => return Response::new(404),
+    };
+    if let Ok(meta) = fs::metadata(&path).await {
+        if meta.is_dir() {
+            path.push(INDEX_FILE);
+        }
+    }
+    let path: PathBuf = match fs::canonicalize(&path).await {
+        Ok(path) => path.into(),
+        Err(_) => return Response::new(404),
+    };
+    if !path.starts_with(root) {
+        log::warn!("Blocked static file {:?} outside of {:?}", path, root);
+        return Response::new(404);
+    }
+    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+    serve_file(&method, &headers, &path, mime).await
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path() {
+        let root = Path::new("/srv/www");
+        assert_eq!(
+            resolve_path(root, "css/site.css"),
+            Some(PathBuf::from("/srv/www/css/site.css"))
+        );
+        assert_eq!(
+            resolve_path(root, "a%20b.txt"),
+            Some(PathBuf::from("/srv/www/a b.txt"))
+        );
+        assert_eq!(resolve_path(root, ""), Some(PathBuf::from("/srv/www")));
+        assert_eq!(resolve_path(root, "../etc/passwd"), None);
+        assert_eq!(resolve_path(root, "css/%2e%2e/%2e%2e/etc"), None);
+        assert_eq!(resolve_path(root, ".git/config"), None);
+        assert_eq!(resolve_path(root, "a%5c..%5cb"), None);
+        assert_eq!(resolve_path(root, "a%00b"), None);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Range::Partial(0, 99));
+        assert_eq!(parse_range("bytes=900-", 1000), Range::Partial(900, 999));
+        assert_eq!(
+            parse_range("bytes=900-2000", 1000),
+            Range::Partial(900, 999)
+        );
+        assert_eq!(parse_range("bytes=-100", 1000), Range::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), Range::Partial(0, 999));
+        assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Range::Full);
+        assert_eq!(parse_range("bytes=5-1", 1000), Range::Full);
+        assert_eq!(parse_range("items=0-1", 1000), Range::Full);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let etag = "\"10-abc\"";
+        let modified = Utc.timestamp(1_445_412_480, 0);
+        assert!(etag_matches("\"1-1\", W/\"10-abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"10-abd\"", etag));
+
+        let since = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert!(not_modified(None, Some(since), etag, Some(modified)));
+        assert!(!not_modified(
+            None,
+            Some("Wed, 21 Oct 2015 07:27:59 GMT"),
+            etag,
+            Some(modified)
+        ));
+        // If-None-Match takes precedence over If-Modified-Since
+        assert!(!not_modified(
+            Some("\"other\""),
+            Some(since),
+            etag,
+            Some(modified)
+        ));
+
+        assert!(if_range_matches(None, etag, Some(modified)));
+        assert!(if_range_matches(Some(etag), etag, Some(modified)));
+        assert!(!if_range_matches(
+            Some("W/\"10-abc\""),
+            etag,
+            Some(modified)
+        ));
+        assert!(if_range_matches(Some(since), etag, Some(modified)));
+        assert!(!if_range_matches(
+            Some("Wed, 21 Oct 2015 07:27:59 GMT"),
+            etag,
+            Some(modified)
+        ));
+    }
+}